@@ -1,8 +1,145 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
 fn main() {
-  let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+  let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
 
   if target_os == "freebsd" {
     println!("cargo:rustc-link-lib=iio");
     println!("cargo:rustc-link-search=native=/usr/local/lib");
   }
+
+  link_libiio(&target_os);
+
+  let git_hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+  let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+
+  println!("cargo:rustc-env=YURED_GIT_HASH={git_hash}");
+  println!(
+    "cargo:rustc-env=YURED_AHRS_VERSION={}",
+    locked_version(&lock, "ahrs")
+  );
+  println!(
+    "cargo:rustc-env=YURED_VQF_VERSION={}",
+    locked_version(&lock, "vqf")
+  );
+  rerun_if_git_head_moves();
+  println!("cargo:rerun-if-changed=Cargo.lock");
+}
+
+fn git(args: &[&str]) -> Option<String> {
+  Command::new("git")
+    .args(args)
+    .output()
+    .ok()
+    .filter(|output| output.status.success())
+    .and_then(|output| String::from_utf8(output.stdout).ok())
+    .map(|out| out.trim().to_string())
+}
+
+// HEAD only changes on checkout; a commit moves the branch ref, which is a
+// loose file until `git pack-refs` folds it into packed-refs. The loose ref is
+// watched even when missing, since the next commit creates it.
+fn rerun_if_git_head_moves() {
+  let Some(head) = git(&["rev-parse", "--git-path", "HEAD"]) else {
+    return;
+  };
+
+  println!("cargo:rerun-if-changed={head}");
+
+  if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"])
+    && let Some(path) = git(&["rev-parse", "--git-path", &branch])
+  {
+    println!("cargo:rerun-if-changed={path}");
+  }
+
+  if let Some(packed) = git(&["rev-parse", "--git-path", "packed-refs"])
+    && Path::new(&packed).exists()
+  {
+    println!("cargo:rerun-if-changed={packed}");
+  }
+}
+
+fn link_libiio(target_os: &str) {
+  for var in [
+    "LIBIIO_LIB_DIR",
+    "LIBIIO_INCLUDE_DIR",
+    "PKG_CONFIG_SYSROOT_DIR",
+    "STAGING_DIR",
+  ] {
+    println!("cargo:rerun-if-env-changed={var}");
+  }
+
+  if let Some(dir) = env::var_os("LIBIIO_LIB_DIR") {
+    println!(
+      "cargo:rustc-link-search=native={}",
+      Path::new(&dir).display()
+    );
+
+    return;
+  }
+
+  let cross = env::var("TARGET").ok() != env::var("HOST").ok();
+
+  if target_os != "linux" || !cross {
+    return;
+  }
+
+  let multiarch = env::var("TARGET")
+    .unwrap_or_default()
+    .replace("-unknown-", "-");
+  let mut candidates = Vec::new();
+
+  // A sibling lib/ of an explicit include dir is the usual staged layout.
+  if let Some(include) = env::var_os("LIBIIO_INCLUDE_DIR") {
+    candidates.push(Path::new(&include).join("../lib"));
+  }
+
+  if let Some(sysroot) = env::var_os("PKG_CONFIG_SYSROOT_DIR") {
+    let sysroot = PathBuf::from(sysroot);
+
+    candidates.push(sysroot.join("usr/lib"));
+    candidates.push(sysroot.join("usr/lib").join(&multiarch));
+    candidates.push(sysroot.join("lib"));
+  }
+
+  if let Some(staging) = env::var_os("STAGING_DIR") {
+    candidates.push(PathBuf::from(staging).join("usr/lib"));
+  }
+
+  match candidates.iter().find(|dir| has_libiio(dir)) {
+    Some(dir) => println!("cargo:rustc-link-search=native={}", dir.display()),
+    None => println!(
+      "cargo:warning=libiio not found for cross target; set LIBIIO_LIB_DIR, PKG_CONFIG_SYSROOT_DIR or STAGING_DIR"
+    ),
+  }
+}
+
+fn has_libiio(dir: &Path) -> bool {
+  std::fs::read_dir(dir).is_ok_and(|entries| {
+    entries.filter_map(Result::ok).any(|entry| {
+      entry
+        .file_name()
+        .to_str()
+        .is_some_and(|name| name.starts_with("libiio.so") || name == "libiio.a")
+    })
+  })
+}
+
+fn locked_version(lock: &str, name: &str) -> String {
+  let needle = format!("name = \"{name}\"");
+  let mut lines = lock.lines();
+
+  while let Some(line) = lines.next() {
+    if line.trim() == needle
+      && let Some(version) = lines
+        .next()
+        .and_then(|line| line.trim().strip_prefix("version = "))
+    {
+      return version.trim_matches('"').to_string();
+    }
+  }
+
+  "unknown".to_string()
 }
@@ -0,0 +1,20 @@
+use crate::error::{Error, Result};
+use nix::time::{ClockId, clock_gettime};
+use std::time::Duration;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const BOOT_CLOCK: ClockId = ClockId::CLOCK_BOOTTIME;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const BOOT_CLOCK: ClockId = ClockId::CLOCK_MONOTONIC;
+
+// CLOCK_BOOTTIME keeps counting through suspend, so a resume shows up as one
+// long dt instead of a silent gap. Kernels before 2.6.39 reject it with EINVAL.
+/// # Errors
+///
+/// Returns an error when neither the boot nor the monotonic clock can be read.
+pub fn boottime_ms() -> Result<f64> {
+  clock_gettime(BOOT_CLOCK)
+    .or_else(|_| clock_gettime(ClockId::CLOCK_MONOTONIC))
+    .map(|ts| Duration::from(ts).as_millis_f64())
+    .map_err(|err| Error::invalid_state_with("failed to read the boot clock", err))
+}
@@ -1,17 +1,36 @@
+use nix::errno::Errno;
 use std::borrow::Cow;
-use std::fmt;
 use std::time::SystemTimeError;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+  #[error("invalid state: {0}")]
   InvalidState(Cow<'static, str>),
-  Time(SystemTimeError),
-  Url(url::ParseError),
-  Json(serde_json::Error),
-  Iio(Box<industrial_io::Error>),
-  Ws(Box<tungstenite::Error>),
-  SensorNotFound,
+  #[error("invalid state: {message}: {source}")]
+  InvalidStateWith {
+    message: Cow<'static, str>,
+    source: Box<dyn std::error::Error + Send + Sync>,
+  },
+  #[error("time error: {0}")]
+  Time(#[from] SystemTimeError),
+  #[error("url parse error: {0}")]
+  Url(#[from] url::ParseError),
+  #[error("json error: {0}")]
+  Json(#[from] serde_json::Error),
+  #[error("iio error: {0}")]
+  Iio(#[source] Box<industrial_io::Error>),
+  #[error("websocket error: {0}")]
+  Ws(#[source] Box<tungstenite::Error>),
+  #[error("websocket handshake rejected: http {0}")]
+  WsRejected(u16),
+  #[error("iio sensor not found{}", module_hint(.0))]
+  SensorNotFound(Vec<String>),
+  #[error("iio trigger not found")]
   IioTriggerNotFound,
+  #[error("iio buffer refill timed out ({0})")]
+  IioTimeout(String),
+  #[error("preflight failed:{0}")]
+  Preflight(String),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -19,52 +38,36 @@ impl Error {
   pub fn invalid_state(message: impl Into<Cow<'static, str>>) -> Self {
     Self::InvalidState(message.into())
   }
-}
 
-impl fmt::Display for Error {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    match self {
-      Self::InvalidState(message) => write!(f, "invalid state: {message}"),
-      Self::Time(err) => write!(f, "time error: {err}"),
-      Self::Url(err) => write!(f, "url parse error: {err}"),
-      Self::Json(err) => write!(f, "json error: {err}"),
-      Self::Iio(err) => write!(f, "iio error: {err}"),
-      Self::Ws(err) => write!(f, "websocket error: {err}"),
-      Self::SensorNotFound => write!(f, "iio sensor not found"),
-      Self::IioTriggerNotFound => write!(f, "iio trigger not found"),
+  pub fn invalid_state_with(
+    message: impl Into<Cow<'static, str>>,
+    source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+  ) -> Self {
+    Self::InvalidStateWith {
+      message: message.into(),
+      source: source.into(),
     }
   }
-}
 
-impl std::error::Error for Error {
-  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+  #[must_use]
+  pub fn is_interrupted(&self) -> bool {
     match self {
-      Self::InvalidState(_) | Self::SensorNotFound | Self::IioTriggerNotFound => None,
-      Self::Time(err) => Some(err),
-      Self::Url(err) => Some(err),
-      Self::Json(err) => Some(err),
-      Self::Iio(err) => Some(err),
-      Self::Ws(err) => Some(err),
+      Self::Iio(err) => match err.as_ref() {
+        industrial_io::Error::Nix(errno) => *errno == Errno::EINTR,
+        industrial_io::Error::Io(err) => err.kind() == std::io::ErrorKind::Interrupted,
+        _ => false,
+      },
+      _ => false,
     }
   }
 }
 
-impl From<SystemTimeError> for Error {
-  fn from(err: SystemTimeError) -> Self {
-    Self::Time(err)
-  }
-}
-
-impl From<url::ParseError> for Error {
-  fn from(err: url::ParseError) -> Self {
-    Self::Url(err)
+fn module_hint(modules: &[String]) -> String {
+  if modules.is_empty() {
+    return String::new();
   }
-}
 
-impl From<serde_json::Error> for Error {
-  fn from(err: serde_json::Error) -> Self {
-    Self::Json(err)
-  }
+  format!("; try loading kernel module(s): {}", modules.join(", "))
 }
 
 impl From<industrial_io::Error> for Error {
@@ -1,15 +1,90 @@
+use crate::sensor::ImuSample;
 use ahrs::Ahrs;
 use clap::ValueEnum;
-use nalgebra::{UnitQuaternion, Vector3};
+use nalgebra::{DMatrix, DVector, Quaternion, UnitQuaternion, Vector3};
 use nalgebra_vqf::{UnitQuaternion as UnitQuaternionVqf, Vector3 as Vector3Vqf};
 use num_traits::ToPrimitive;
 use std::{fmt::Display, time::Duration};
 
+const MADGWICK_BETA: f64 = 0.1;
+const MAHONY_KP: f64 = 0.5;
+const MAHONY_KI: f64 = 0.0;
+const GIMBAL_LOCK_SIN: f64 = 0.999_999;
+const LOWPASS_GRAVITY_TAU_SEC: f64 = 1.0;
+const STANDARD_GRAVITY: f64 = 9.806_65;
+// Standard deviation of |accel| around g, as a fraction of g, for adaptive gain.
+const ACCEL_TRUST_SIGMA: f64 = 0.15;
+pub const EKF_PROCESS_NOISE: f64 = 1e-4;
+pub const EKF_MEASUREMENT_NOISE: f64 = 1e-2;
+// Gyro bias random walk relative to the quaternion process noise.
+const EKF_BIAS_NOISE_RATIO: f64 = 0.01;
+const EKF_INITIAL_VARIANCE: f64 = 1e-2;
+const EKF_STATE_LEN: usize = 7;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
 pub enum Algorithm {
   Madgwick,
   Mahony,
   Vqf,
+  Ekf,
+  None,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum GravitySource {
+  #[default]
+  Fusion,
+  Lowpass,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum GravityDirection {
+  #[value(name = "+x")]
+  PosX,
+  #[value(name = "-x")]
+  NegX,
+  #[value(name = "+y")]
+  PosY,
+  #[value(name = "-y")]
+  NegY,
+  #[default]
+  #[value(name = "+z")]
+  PosZ,
+  #[value(name = "-z")]
+  NegZ,
+}
+
+impl GravityDirection {
+  // Proper rotations taking the mounting axis onto +z, where every filter
+  // expects gravity at rest.
+  fn to_filter_frame(self, [x, y, z]: [f64; 3]) -> [f64; 3] {
+    match self {
+      Self::PosX => [-z, y, x],
+      Self::NegX => [z, y, -x],
+      Self::PosY => [x, -z, y],
+      Self::NegY => [x, z, -y],
+      Self::PosZ => [x, y, z],
+      Self::NegZ => [x, -y, -z],
+    }
+  }
+
+  fn to_body_frame(self, [x, y, z]: [f64; 3]) -> [f64; 3] {
+    match self {
+      Self::PosX => [z, y, -x],
+      Self::NegX => [-z, y, x],
+      Self::PosY => [x, z, -y],
+      Self::NegY => [x, -z, y],
+      Self::PosZ => [x, y, z],
+      Self::NegZ => [x, -y, -z],
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Tilt {
+  pub pitch: f64,
+  pub roll: f64,
+  pub yaw: Option<f64>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -20,12 +95,78 @@ enum GravitySign {
 }
 
 pub struct FusionEngine {
+  algorithm: Algorithm,
+  gravity_source: GravitySource,
   inner: Box<dyn GravityEstimator>,
   gravity_sign: GravitySign,
+  flip_evidence: u32,
+  flip_debounce: u32,
+  rate_hz: u32,
+  updates: u64,
+  mag_used: bool,
+  gyro_temp: Option<GyroTempCompensation>,
+  saturated_updates: u64,
+  adaptive_gain: bool,
+  fixed_dt: bool,
+  gravity_direction: GravityDirection,
+  ekf_noise: EkfNoise,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EkfNoise {
+  pub process: f64,
+  pub measurement: f64,
 }
 
-trait GravityEstimator {
-  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], dt_sec: f64) -> [f64; 3];
+struct LowPassGravity {
+  tau_sec: f64,
+  gravity: Option<[f64; 3]>,
+}
+
+// State is the body-to-earth quaternion (w, x, y, z) followed by the gyro
+// bias in rad/s; the accel measurement is the normalized gravity direction.
+struct EkfEstimator {
+  state: DVector<f64>,
+  covariance: DMatrix<f64>,
+  noise: EkfNoise,
+  accel_trust: f64,
+  initialized: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct GyroTempCompensation {
+  slope_rad_s_per_c: f64,
+  reference_c: f64,
+}
+
+trait GravityEstimator: Send {
+  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], dt_sec: f64);
+
+  fn update_with_mag(
+    &mut self,
+    accel_mps2: [f64; 3],
+    gyro_rad_s: [f64; 3],
+    _mag_ut: [f64; 3],
+    dt_sec: f64,
+  ) {
+    self.update(accel_mps2, gyro_rad_s, dt_sec);
+  }
+
+  fn supports_mag(&self) -> bool {
+    false
+  }
+
+  fn set_accel_trust(&mut self, _trust: f64) {}
+
+  fn update_gyro_only(&mut self, gyro_rad_s: [f64; 3], dt_sec: f64);
+
+  fn gravity(&self) -> [f64; 3];
+
+  fn orientation_wxyz(&self) -> [f64; 4];
+
+  fn convergence_score(&self, updates: u64, rate_hz: u32) -> f64 {
+    time_convergence(updates, rate_hz)
+  }
 }
 
 impl GravitySign {
@@ -51,34 +192,222 @@ impl Display for Algorithm {
       Algorithm::Madgwick => "madgwick",
       Algorithm::Mahony => "mahony",
       Algorithm::Vqf => "vqf",
+      Algorithm::Ekf => "ekf",
+      Algorithm::None => "none",
     })
   }
 }
 
 impl FusionEngine {
-  pub fn new(algorithm: Algorithm, rate_hz: u32) -> Self {
-    let sample_period = 1.0 / f64::from(rate_hz);
-    let inner: Box<dyn GravityEstimator> = match algorithm {
-      Algorithm::Madgwick => Box::new(ahrs::Madgwick::new(sample_period, 0.1)),
-      Algorithm::Mahony => Box::new(ahrs::Mahony::new(sample_period, 0.5, 0.0)),
-      Algorithm::Vqf => {
-        let period = Duration::from_secs_f64(sample_period);
-
-        Box::new(vqf::Vqf::new(period, period, vqf::VqfParameters::default()))
-      }
-    };
+  #[must_use]
+  pub fn new(algorithm: Algorithm, rate_hz: u32) -> Option<Self> {
+    let inner = build_estimator(
+      algorithm,
+      GravitySource::Fusion,
+      rate_hz,
+      EkfNoise::default(),
+    )?;
 
-    Self {
+    Some(Self {
+      algorithm,
+      gravity_source: GravitySource::Fusion,
       inner,
       gravity_sign: GravitySign::Unknown,
+      flip_evidence: 0,
+      flip_debounce: (rate_hz / 2).max(1),
+      rate_hz,
+      updates: 0,
+      mag_used: false,
+      gyro_temp: None,
+      saturated_updates: 0,
+      adaptive_gain: false,
+      fixed_dt: false,
+      gravity_direction: GravityDirection::PosZ,
+      ekf_noise: EkfNoise::default(),
+    })
+  }
+
+  #[must_use]
+  pub fn with_gyro_temp_slope(mut self, slope_deg_s_per_c: f64, reference_c: f64) -> Self {
+    self.gyro_temp = Some(GyroTempCompensation {
+      slope_rad_s_per_c: slope_deg_s_per_c.to_radians(),
+      reference_c,
+    });
+    self
+  }
+
+  #[must_use]
+  pub fn with_adaptive_gain(mut self, adaptive_gain: bool) -> Self {
+    self.adaptive_gain = adaptive_gain;
+    self
+  }
+
+  #[must_use]
+  pub fn with_fixed_dt(mut self, fixed_dt: bool) -> Self {
+    self.fixed_dt = fixed_dt;
+    self
+  }
+
+  // vqf is built with the nominal period and ignores the dt it is handed.
+  #[must_use]
+  pub fn ignores_fixed_dt(&self) -> bool {
+    self.algorithm == Algorithm::Vqf && self.gravity_source == GravitySource::Fusion
+  }
+
+  #[must_use]
+  pub fn with_gravity_direction(mut self, gravity_direction: GravityDirection) -> Self {
+    self.gravity_direction = gravity_direction;
+    self
+  }
+
+  #[must_use]
+  pub fn with_ekf_noise(mut self, ekf_noise: EkfNoise) -> Self {
+    self.ekf_noise = ekf_noise;
+    self.reset();
+    self
+  }
+
+  #[must_use]
+  pub fn with_gravity_source(mut self, gravity_source: GravitySource) -> Self {
+    self.gravity_source = gravity_source;
+    self.reset();
+    self
+  }
+
+  // Estimators are distinct concrete types behind the box, so the state is
+  // rebuilt in a fresh allocation rather than overwritten in place.
+  pub fn reset(&mut self) {
+    if let Some(inner) = build_estimator(
+      self.algorithm,
+      self.gravity_source,
+      self.rate_hz,
+      self.ekf_noise,
+    ) {
+      self.inner = inner;
+    }
+
+    self.gravity_sign = GravitySign::Unknown;
+    self.flip_evidence = 0;
+    self.updates = 0;
+    self.mag_used = false;
+  }
+
+  #[must_use]
+  pub fn describe(&self) -> String {
+    if self.gravity_source == GravitySource::Lowpass {
+      return format!(
+        "lowpass gravity at {}hz (tau={LOWPASS_GRAVITY_TAU_SEC}s)",
+        self.rate_hz
+      );
+    }
+
+    let gains = match self.algorithm {
+      Algorithm::Madgwick => format!("beta={MADGWICK_BETA}"),
+      Algorithm::Mahony => format!("kp={MAHONY_KP} ki={MAHONY_KI}"),
+      Algorithm::Vqf => "default parameters".to_string(),
+      Algorithm::Ekf => format!(
+        "process_noise={} measurement_noise={}",
+        self.ekf_noise.process, self.ekf_noise.measurement
+      ),
+      Algorithm::None => "passthrough".to_string(),
+    };
+
+    format!("{} at {}hz ({gains})", self.algorithm, self.rate_hz)
+  }
+
+  // Updates that skipped the accel correction because it was saturated or non-finite.
+  #[must_use]
+  pub fn saturated_updates(&self) -> u64 {
+    self.saturated_updates
+  }
+
+  #[must_use]
+  pub fn convergence_score(&self) -> f64 {
+    self.inner.convergence_score(self.updates, self.rate_hz)
+  }
+
+  #[must_use]
+  pub fn orientation_wxyz(&self) -> [f64; 4] {
+    self.inner.orientation_wxyz()
+  }
+
+  #[must_use]
+  pub fn gravity_sign(&self) -> &'static str {
+    match self.gravity_sign {
+      GravitySign::Unknown => "unknown",
+      GravitySign::Positive => "pos",
+      GravitySign::Negative => "neg",
+    }
+  }
+
+  #[must_use]
+  pub fn tilt(&self) -> Tilt {
+    tilt_from_quaternion(self.inner.orientation_wxyz(), self.mag_used)
+  }
+
+  #[must_use]
+  pub fn compensate_gyro(&self, mut gyro: [f64; 3], temp_c: Option<f64>) -> [f64; 3] {
+    if let (Some(comp), Some(temp_c)) = (self.gyro_temp, temp_c) {
+      let bias = comp.slope_rad_s_per_c * (temp_c - comp.reference_c);
+
+      for v in &mut gyro {
+        *v -= bias;
+      }
     }
+
+    gyro
   }
 
-  pub fn update(&mut self, accel_mps2: [f64; 3], gyro: Option<[f64; 3]>, dt_sec: f64) -> [f64; 3] {
-    let gyro = gyro.unwrap_or([0.0; 3]);
-    let g_body = self.inner.update(accel_mps2, gyro, dt_sec);
+  // `periods` is how many sensor periods the sample stands for, more than one
+  // when idle downclock merged skipped samples into it.
+  pub fn update(&mut self, sample: &ImuSample, periods: u32) -> [f64; 3] {
+    let ImuSample {
+      accel_mps2,
+      gyro,
+      mag_ut,
+      temp_c,
+      ..
+    } = *sample;
+    let saturated =
+      gyro.is_some() && (sample.accel_saturated || accel_mps2.iter().any(|v| !v.is_finite()));
+    let dt_sec = if self.fixed_dt {
+      f64::from(periods.max(1)) / f64::from(self.rate_hz.max(1))
+    } else {
+      sample.dt_sec
+    };
+    let direction = self.gravity_direction;
+    let gyro = direction.to_filter_frame(self.compensate_gyro(gyro.unwrap_or([0.0; 3]), temp_c));
+    let accel = direction.to_filter_frame(accel_mps2);
+
+    if self.adaptive_gain && !saturated {
+      self
+        .inner
+        .set_accel_trust(accel_trust(accel, STANDARD_GRAVITY));
+    }
+
+    match mag_ut.map(|mag_ut| direction.to_filter_frame(mag_ut)) {
+      _ if saturated => self.inner.update_gyro_only(gyro, dt_sec),
+      Some(mag_ut) => self.inner.update_with_mag(accel, gyro, mag_ut, dt_sec),
+      None => self.inner.update(accel, gyro, dt_sec),
+    }
+
+    let g_body = direction.to_body_frame(self.inner.gravity());
+
+    self.mag_used = mag_ut.is_some() && self.inner.supports_mag();
 
-    maybe_calibrate_gravity_sign(&mut self.gravity_sign, accel_mps2, g_body);
+    self.updates = self.updates.saturating_add(1);
+
+    if saturated {
+      self.saturated_updates = self.saturated_updates.saturating_add(1);
+    } else {
+      maybe_calibrate_gravity_sign(
+        &mut self.gravity_sign,
+        &mut self.flip_evidence,
+        self.flip_debounce,
+        accel_mps2,
+        g_body,
+      );
+    }
 
     let factor = self.gravity_sign.factor();
 
@@ -87,7 +416,7 @@ impl FusionEngine {
 }
 
 impl GravityEstimator for ahrs::Madgwick<f64> {
-  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], dt_sec: f64) -> [f64; 3] {
+  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], dt_sec: f64) {
     let dt = dt_sec.max(0.0);
 
     if dt > 0.0 {
@@ -99,13 +428,32 @@ impl GravityEstimator for ahrs::Madgwick<f64> {
         let _ = self.update_gyro(&gyro);
       }
     }
+  }
+
+  fn update_gyro_only(&mut self, gyro_rad_s: [f64; 3], dt_sec: f64) {
+    let dt = dt_sec.max(0.0);
+
+    if dt > 0.0 {
+      *self.sample_period_mut() = dt;
+      let _ = self.update_gyro(&Vector3::new(gyro_rad_s[0], gyro_rad_s[1], gyro_rad_s[2]));
+    }
+  }
+
+  fn set_accel_trust(&mut self, trust: f64) {
+    *self.beta_mut() = MADGWICK_BETA * trust;
+  }
 
+  fn gravity(&self) -> [f64; 3] {
     gravity_from_orientation_f64(&self.quat)
   }
+
+  fn orientation_wxyz(&self) -> [f64; 4] {
+    quaternion_wxyz_f64(&self.quat)
+  }
 }
 
 impl GravityEstimator for ahrs::Mahony<f64> {
-  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], dt_sec: f64) -> [f64; 3] {
+  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], dt_sec: f64) {
     let dt = dt_sec.max(0.0);
 
     if dt > 0.0 {
@@ -117,27 +465,373 @@ impl GravityEstimator for ahrs::Mahony<f64> {
         let _ = self.update_gyro(&gyro);
       }
     }
+  }
+
+  fn update_with_mag(
+    &mut self,
+    accel_mps2: [f64; 3],
+    gyro_rad_s: [f64; 3],
+    mag_ut: [f64; 3],
+    dt_sec: f64,
+  ) {
+    let dt = dt_sec.max(0.0);
+
+    if dt > 0.0 {
+      *self.sample_period_mut() = dt;
+      let gyro = Vector3::new(gyro_rad_s[0], gyro_rad_s[1], gyro_rad_s[2]);
+      let accel = Vector3::new(accel_mps2[0], accel_mps2[1], accel_mps2[2]);
+      let mag = Vector3::new(mag_ut[0], mag_ut[1], mag_ut[2]);
+
+      if Ahrs::update(self, &gyro, &accel, &mag).is_err() && self.update_imu(&gyro, &accel).is_err()
+      {
+        let _ = self.update_gyro(&gyro);
+      }
+    }
+  }
+
+  fn set_accel_trust(&mut self, trust: f64) {
+    *self.kp_mut() = MAHONY_KP * trust;
+  }
+
+  fn supports_mag(&self) -> bool {
+    true
+  }
+
+  fn update_gyro_only(&mut self, gyro_rad_s: [f64; 3], dt_sec: f64) {
+    let dt = dt_sec.max(0.0);
+
+    if dt > 0.0 {
+      *self.sample_period_mut() = dt;
+      let _ = self.update_gyro(&Vector3::new(gyro_rad_s[0], gyro_rad_s[1], gyro_rad_s[2]));
+    }
+  }
 
+  fn gravity(&self) -> [f64; 3] {
     gravity_from_orientation_f64(&self.quat)
   }
+
+  fn orientation_wxyz(&self) -> [f64; 4] {
+    quaternion_wxyz_f64(&self.quat)
+  }
 }
 
 impl GravityEstimator for vqf::Vqf {
-  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], _dt_sec: f64) -> [f64; 3] {
-    let Some(accel) = vec3_vqf_f32(accel_mps2) else {
-      return gravity_from_orientation_vqf(&self.orientation());
-    };
-    let Some(gyro) = vec3_vqf_f32(gyro_rad_s) else {
-      return gravity_from_orientation_vqf(&self.orientation());
+  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], _dt_sec: f64) {
+    let (Some(accel), Some(gyro)) = (vec3_vqf_f32(accel_mps2), vec3_vqf_f32(gyro_rad_s)) else {
+      return;
     };
 
     self.update(gyro, accel);
+  }
 
+  fn update_gyro_only(&mut self, gyro_rad_s: [f64; 3], _dt_sec: f64) {
+    if let Some(gyro) = vec3_vqf_f32(gyro_rad_s) {
+      self.gyroscope_update(gyro);
+    }
+  }
+
+  fn gravity(&self) -> [f64; 3] {
     gravity_from_orientation_vqf(&self.orientation())
   }
+
+  fn orientation_wxyz(&self) -> [f64; 4] {
+    let q = self.orientation();
+
+    [
+      f64::from(q.w),
+      f64::from(q.i),
+      f64::from(q.j),
+      f64::from(q.k),
+    ]
+  }
+
+  fn convergence_score(&self, updates: u64, rate_hz: u32) -> f64 {
+    if self.is_rest_phase() {
+      1.0
+    } else {
+      time_convergence(updates, rate_hz)
+    }
+  }
+}
+
+impl Default for EkfNoise {
+  fn default() -> Self {
+    Self {
+      process: EKF_PROCESS_NOISE,
+      measurement: EKF_MEASUREMENT_NOISE,
+    }
+  }
 }
 
-fn maybe_calibrate_gravity_sign(sign: &mut GravitySign, accel_mps2: [f64; 3], g_body: [f64; 3]) {
+impl EkfEstimator {
+  fn new(noise: EkfNoise) -> Self {
+    Self {
+      state: DVector::from_row_slice(&[1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+      covariance: DMatrix::identity(EKF_STATE_LEN, EKF_STATE_LEN) * EKF_INITIAL_VARIANCE,
+      noise,
+      accel_trust: 1.0,
+      initialized: false,
+    }
+  }
+
+  fn quaternion(&self) -> [f64; 4] {
+    [self.state[0], self.state[1], self.state[2], self.state[3]]
+  }
+
+  // q' = q + dt/2 * q (x) (0, gyro - bias)
+  fn predict(&mut self, gyro_rad_s: [f64; 3], dt: f64) {
+    let [w, x, y, z] = self.quaternion();
+    let omega = [
+      gyro_rad_s[0] - self.state[4],
+      gyro_rad_s[1] - self.state[5],
+      gyro_rad_s[2] - self.state[6],
+    ];
+    let half_dt = 0.5 * dt;
+    #[rustfmt::skip]
+    let xi = DMatrix::from_row_slice(4, 3, &[
+      -x, -y, -z,
+       w, -z,  y,
+       z,  w, -x,
+      -y,  x,  w,
+    ]);
+    #[rustfmt::skip]
+    let omega_right = DMatrix::from_row_slice(4, 4, &[
+      0.0,      -omega[0], -omega[1], -omega[2],
+      omega[0],  0.0,       omega[2], -omega[1],
+      omega[1], -omega[2],  0.0,       omega[0],
+      omega[2],  omega[1], -omega[0],  0.0,
+    ]);
+    let dq = &xi * DVector::from_row_slice(&omega) * half_dt;
+    let mut jacobian = DMatrix::identity(EKF_STATE_LEN, EKF_STATE_LEN);
+
+    jacobian
+      .view_mut((0, 0), (4, 4))
+      .copy_from(&(DMatrix::identity(4, 4) + omega_right * half_dt));
+    jacobian
+      .view_mut((0, 4), (4, 3))
+      .copy_from(&(xi * -half_dt));
+
+    for i in 0..4 {
+      self.state[i] += dq[i];
+    }
+
+    let mut process = DMatrix::zeros(EKF_STATE_LEN, EKF_STATE_LEN);
+
+    for i in 0..EKF_STATE_LEN {
+      let ratio = if i < 4 { 1.0 } else { EKF_BIAS_NOISE_RATIO };
+
+      process[(i, i)] = self.noise.process * ratio * dt;
+    }
+
+    self.covariance = &jacobian * &self.covariance * jacobian.transpose() + process;
+    self.normalize();
+  }
+
+  fn correct(&mut self, accel_mps2: [f64; 3]) {
+    let Some(measured) = unit_vector(accel_mps2) else {
+      return;
+    };
+
+    if !self.initialized {
+      self.initialized = true;
+
+      for (i, v) in quaternion_from_gravity(measured).into_iter().enumerate() {
+        self.state[i] = v;
+      }
+
+      return;
+    }
+
+    let [w, x, y, z] = self.quaternion();
+    let expected = [
+      2.0 * (x * z - w * y),
+      2.0 * (y * z + w * x),
+      w * w - x * x - y * y + z * z,
+    ];
+    #[rustfmt::skip]
+    let jacobian = DMatrix::from_row_slice(3, EKF_STATE_LEN, &[
+      -2.0 * y,  2.0 * z, -2.0 * w, 2.0 * x, 0.0, 0.0, 0.0,
+       2.0 * x,  2.0 * w,  2.0 * z, 2.0 * y, 0.0, 0.0, 0.0,
+       2.0 * w, -2.0 * x, -2.0 * y, 2.0 * z, 0.0, 0.0, 0.0,
+    ]);
+    let measurement = DMatrix::identity(3, 3) * (self.noise.measurement / self.accel_trust);
+    let innovation_cov = &jacobian * &self.covariance * jacobian.transpose() + measurement;
+    // S and P are symmetric, so K^T = S^-1 (H P).
+    let Some(gain_t) = innovation_cov.lu().solve(&(&jacobian * &self.covariance)) else {
+      return;
+    };
+    let gain = gain_t.transpose();
+    let innovation = DVector::from_iterator(3, measured.iter().zip(expected).map(|(m, e)| m - e));
+
+    self.state += &gain * innovation;
+    self.covariance =
+      (DMatrix::identity(EKF_STATE_LEN, EKF_STATE_LEN) - &gain * jacobian) * &self.covariance;
+    self.covariance = (&self.covariance + self.covariance.transpose()) * 0.5;
+    self.normalize();
+  }
+
+  fn normalize(&mut self) {
+    let norm = self.state.rows(0, 4).norm();
+
+    if norm > 0.0 && norm.is_finite() {
+      for i in 0..4 {
+        self.state[i] /= norm;
+      }
+    } else {
+      *self = Self::new(self.noise);
+    }
+  }
+}
+
+impl GravityEstimator for EkfEstimator {
+  fn update(&mut self, accel_mps2: [f64; 3], gyro_rad_s: [f64; 3], dt_sec: f64) {
+    let dt = dt_sec.max(0.0);
+
+    if dt > 0.0 {
+      if self.initialized {
+        self.predict(gyro_rad_s, dt);
+      }
+
+      self.correct(accel_mps2);
+    }
+  }
+
+  fn update_gyro_only(&mut self, gyro_rad_s: [f64; 3], dt_sec: f64) {
+    let dt = dt_sec.max(0.0);
+
+    if dt > 0.0 && self.initialized {
+      self.predict(gyro_rad_s, dt);
+    }
+  }
+
+  fn set_accel_trust(&mut self, trust: f64) {
+    self.accel_trust = trust.max(1e-3);
+  }
+
+  fn gravity(&self) -> [f64; 3] {
+    let [w, x, y, z] = self.quaternion();
+
+    gravity_from_orientation_f64(&UnitQuaternion::from_quaternion(Quaternion::new(
+      w, x, y, z,
+    )))
+  }
+
+  fn orientation_wxyz(&self) -> [f64; 4] {
+    self.quaternion()
+  }
+}
+
+impl GravityEstimator for LowPassGravity {
+  fn update(&mut self, accel_mps2: [f64; 3], _gyro_rad_s: [f64; 3], dt_sec: f64) {
+    let Some(gravity) = self.gravity.as_mut() else {
+      self.gravity = Some(accel_mps2);
+
+      return;
+    };
+    let dt = dt_sec.max(0.0);
+    let alpha = dt / (self.tau_sec + dt);
+
+    for (g, a) in gravity.iter_mut().zip(accel_mps2) {
+      *g += alpha * (a - *g);
+    }
+  }
+
+  fn update_gyro_only(&mut self, _gyro_rad_s: [f64; 3], _dt_sec: f64) {}
+
+  fn gravity(&self) -> [f64; 3] {
+    self.gravity.unwrap_or([0.0; 3])
+  }
+
+  fn orientation_wxyz(&self) -> [f64; 4] {
+    let Some(gravity) = unit_vector(self.gravity.unwrap_or([0.0, 0.0, 1.0])) else {
+      return [1.0, 0.0, 0.0, 0.0];
+    };
+
+    quaternion_from_gravity(gravity)
+  }
+}
+
+fn unit_vector([x, y, z]: [f64; 3]) -> Option<[f64; 3]> {
+  let norm = (x * x + y * y + z * z).sqrt();
+
+  (norm > 0.0 && norm.is_finite()).then(|| [x / norm, y / norm, z / norm])
+}
+
+// Shortest rotation whose inverse takes earth +z onto the given unit vector.
+fn quaternion_from_gravity([x, y, z]: [f64; 3]) -> [f64; 4] {
+  let w = 1.0 + z;
+
+  if w < 1e-9 {
+    return [0.0, 1.0, 0.0, 0.0];
+  }
+
+  let norm = (w * w + y * y + x * x).sqrt();
+
+  [w / norm, y / norm, -x / norm, 0.0]
+}
+
+fn time_convergence(updates: u64, rate_hz: u32) -> f64 {
+  const TAU_SEC: f64 = 5.0;
+
+  let updates = f64::from(u32::try_from(updates).unwrap_or(u32::MAX));
+
+  1.0 - (-updates / (f64::from(rate_hz) * TAU_SEC)).exp()
+}
+
+fn accel_trust(accel_mps2: [f64; 3], gravity_magnitude: f64) -> f64 {
+  let norm = accel_mps2.iter().map(|v| v * v).sum::<f64>().sqrt();
+  let deviation = (norm - gravity_magnitude) / (ACCEL_TRUST_SIGMA * gravity_magnitude);
+
+  if !deviation.is_finite() {
+    return 0.0;
+  }
+
+  (-0.5 * deviation * deviation).exp()
+}
+
+fn build_estimator(
+  algorithm: Algorithm,
+  gravity_source: GravitySource,
+  rate_hz: u32,
+  ekf_noise: EkfNoise,
+) -> Option<Box<dyn GravityEstimator>> {
+  let sample_period = 1.0 / f64::from(rate_hz);
+
+  if gravity_source == GravitySource::Lowpass && algorithm != Algorithm::None {
+    return Some(Box::new(LowPassGravity {
+      tau_sec: LOWPASS_GRAVITY_TAU_SEC,
+      gravity: None,
+    }));
+  }
+
+  match algorithm {
+    Algorithm::Madgwick => Some(Box::new(ahrs::Madgwick::new(sample_period, MADGWICK_BETA))),
+    Algorithm::Mahony => Some(Box::new(ahrs::Mahony::new(
+      sample_period,
+      MAHONY_KP,
+      MAHONY_KI,
+    ))),
+    Algorithm::Vqf => {
+      let period = Duration::from_secs_f64(sample_period);
+
+      Some(Box::new(vqf::Vqf::new(
+        period,
+        period,
+        vqf::VqfParameters::default(),
+      )))
+    }
+    Algorithm::Ekf => Some(Box::new(EkfEstimator::new(ekf_noise))),
+    Algorithm::None => None,
+  }
+}
+
+fn maybe_calibrate_gravity_sign(
+  sign: &mut GravitySign,
+  flip_evidence: &mut u32,
+  flip_debounce: u32,
+  accel_mps2: [f64; 3],
+  g_body: [f64; 3],
+) {
   let accel_norm = (accel_mps2[0].powi(2) + accel_mps2[1].powi(2) + accel_mps2[2].powi(2)).sqrt();
   let g = 9.806_65;
 
@@ -160,7 +854,16 @@ fn maybe_calibrate_gravity_sign(sign: &mut GravitySign, accel_mps2: [f64; 3], g_
 
   let signed_dot = dot * sign.factor();
 
-  if signed_dot < -0.8 * gg {
+  if signed_dot >= -0.8 * gg {
+    *flip_evidence = 0;
+
+    return;
+  }
+
+  *flip_evidence += 1;
+
+  if *flip_evidence >= flip_debounce {
+    *flip_evidence = 0;
     *sign = match *sign {
       GravitySign::Positive => GravitySign::Negative,
       GravitySign::Negative => GravitySign::Positive,
@@ -178,7 +881,7 @@ fn vec3_vqf_f32(v: [f64; 3]) -> Option<Vector3Vqf<f32>> {
 }
 
 fn gravity_from_orientation_f64(q_body_to_earth: &UnitQuaternion<f64>) -> [f64; 3] {
-  let g_earth = Vector3::new(0.0, 0.0, 9.806_65);
+  let g_earth = Vector3::new(0.0, 0.0, STANDARD_GRAVITY);
   let g_body = q_body_to_earth.inverse_transform_vector(&g_earth);
 
   [g_body.x, g_body.y, g_body.z]
@@ -194,3 +897,164 @@ fn gravity_from_orientation_vqf(q_body_to_earth: &UnitQuaternionVqf<f32>) -> [f6
     f64::from(g_body.z),
   ]
 }
+
+fn quaternion_wxyz_f64(q: &UnitQuaternion<f64>) -> [f64; 4] {
+  [q.w, q.i, q.j, q.k]
+}
+
+fn tilt_from_quaternion([w, x, y, z]: [f64; 4], with_yaw: bool) -> Tilt {
+  let sin_pitch = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0);
+  let (roll, yaw) = if sin_pitch.abs() >= GIMBAL_LOCK_SIN {
+    (0.0, -2.0 * sin_pitch.signum() * x.atan2(w))
+  } else {
+    (
+      (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y)),
+      (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z)),
+    )
+  };
+
+  Tilt {
+    pitch: sin_pitch.asin().to_degrees(),
+    roll: roll.to_degrees(),
+    yaw: with_yaw.then(|| yaw.to_degrees()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    Algorithm, EkfEstimator, EkfNoise, FusionEngine, GravityDirection, GravityEstimator as _,
+    STANDARD_GRAVITY,
+  };
+  use crate::sensor::ImuSample;
+  use clap::ValueEnum as _;
+
+  // The remaps only permute and negate components, so they are exact.
+  #[test]
+  #[allow(clippy::float_cmp)]
+  fn mounting_axis_maps_onto_filter_z_and_back() {
+    for &direction in GravityDirection::value_variants() {
+      let v = [0.3, -1.2, 4.5];
+      let rest = match direction {
+        GravityDirection::PosX => [1.0, 0.0, 0.0],
+        GravityDirection::NegX => [-1.0, 0.0, 0.0],
+        GravityDirection::PosY => [0.0, 1.0, 0.0],
+        GravityDirection::NegY => [0.0, -1.0, 0.0],
+        GravityDirection::PosZ => [0.0, 0.0, 1.0],
+        GravityDirection::NegZ => [0.0, 0.0, -1.0],
+      };
+
+      assert_eq!(
+        direction.to_filter_frame(rest),
+        [0.0, 0.0, 1.0],
+        "{direction:?}"
+      );
+      assert_eq!(
+        direction.to_body_frame(direction.to_filter_frame(v)),
+        v,
+        "{direction:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn gravity_follows_a_sideways_mount() {
+    for (direction, accel) in [
+      (GravityDirection::PosX, [STANDARD_GRAVITY, 0.0, 0.0]),
+      (GravityDirection::NegY, [0.0, -STANDARD_GRAVITY, 0.0]),
+      (GravityDirection::NegZ, [0.0, 0.0, -STANDARD_GRAVITY]),
+    ] {
+      let mut fusion = FusionEngine::new(Algorithm::Madgwick, 100)
+        .unwrap()
+        .with_gravity_direction(direction);
+      let sample = ImuSample {
+        accel_mps2: accel,
+        gyro: Some([0.0; 3]),
+        dt_sec: 0.01,
+        ..ImuSample::default()
+      };
+      let mut gravity = [0.0; 3];
+
+      for _ in 0..500 {
+        gravity = fusion.update(&sample, 1);
+      }
+
+      for (g, a) in gravity.iter().zip(accel) {
+        assert!(
+          (g - a).abs() < 0.05,
+          "{direction:?}: gravity {gravity:?} vs accel {accel:?}"
+        );
+      }
+    }
+  }
+
+  fn assert_gravity_near(gravity: [f64; 3], accel: [f64; 3], context: &str) {
+    for (g, a) in gravity.iter().zip(accel) {
+      assert!(
+        (g - a).abs() < 0.05,
+        "{context}: gravity {gravity:?} vs accel {accel:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn ekf_converges_after_a_tilt() {
+    let mut ekf = EkfEstimator::new(EkfNoise::default());
+    let level = [0.0, 0.0, STANDARD_GRAVITY];
+    let (sin, cos) = 30_f64.to_radians().sin_cos();
+    let tilted = [0.0, STANDARD_GRAVITY * sin, STANDARD_GRAVITY * cos];
+
+    for _ in 0..100 {
+      ekf.update(level, [0.0; 3], 0.01);
+    }
+
+    assert_gravity_near(ekf.gravity(), level, "level");
+
+    for _ in 0..3000 {
+      ekf.update(tilted, [0.0; 3], 0.01);
+    }
+
+    assert_gravity_near(ekf.gravity(), tilted, "tilted");
+  }
+
+  #[test]
+  fn ekf_learns_a_constant_gyro_bias() {
+    let mut ekf = EkfEstimator::new(EkfNoise::default());
+    let level = [0.0, 0.0, STANDARD_GRAVITY];
+    let offset = [0.02, -0.01, 0.0];
+
+    for _ in 0..6000 {
+      ekf.update(level, offset, 0.01);
+    }
+
+    // Yaw bias is unobservable from gravity alone, so only roll and pitch are checked.
+    for axis in 0..2 {
+      assert!(
+        (ekf.state[4 + axis] - offset[axis]).abs() < 0.005,
+        "bias {:?} vs offset {offset:?}",
+        ekf.state.rows(4, 3)
+      );
+    }
+
+    assert_gravity_near(ekf.gravity(), level, "biased gyro");
+  }
+
+  #[test]
+  fn ekf_resets_on_a_non_finite_update() {
+    let mut ekf = EkfEstimator::new(EkfNoise::default());
+    let level = [0.0, 0.0, STANDARD_GRAVITY];
+    let (sin, cos) = 30_f64.to_radians().sin_cos();
+    let tilted = [0.0, STANDARD_GRAVITY * sin, STANDARD_GRAVITY * cos];
+
+    for _ in 0..500 {
+      ekf.update(tilted, [0.01, 0.0, 0.0], 0.01);
+    }
+
+    // The NaN prediction resets the filter and the same sample seeds it again.
+    ekf.update(level, [f64::NAN; 3], 0.01);
+
+    assert!(ekf.state.iter().all(|v| v.is_finite()));
+    assert!(ekf.state.rows(4, 3).iter().all(|&bias| bias == 0.0));
+    assert_gravity_near(ekf.gravity(), level, "after reset");
+  }
+}
@@ -0,0 +1,57 @@
+use super::types::DeviceAttr;
+use industrial_io as iio;
+use std::fs;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::Path;
+
+const SYSFS_IIO_DEVICES: &str = "/sys/bus/iio/devices";
+
+// Writability comes from the sysfs mode bits, so it is only known for local
+// devices; listing attributes never writes to them.
+pub fn enumerate_device_attrs(ctx: &iio::Context, dev: &iio::Device) -> Vec<DeviceAttr> {
+  let sysfs_dir = dev
+    .id()
+    .filter(|_| ctx.name() == "local")
+    .map(|id| Path::new(SYSFS_IIO_DEVICES).join(id));
+  let mut attrs: Vec<DeviceAttr> = dev
+    .attributes()
+    .map(|name| {
+      let value = dev
+        .attr_read_str(&name)
+        .ok()
+        .map(|value| value.trim().to_string());
+      let writable = sysfs_dir.as_ref().and_then(|dir| {
+        let mode = fs::metadata(dir.join(&name)).ok()?.permissions().mode();
+
+        Some(mode & 0o222 != 0)
+      });
+
+      DeviceAttr {
+        kind: value.as_deref().map_or("unreadable", attr_kind),
+        name,
+        value,
+        writable,
+      }
+    })
+    .collect();
+
+  attrs.sort_by(|a, b| a.name.cmp(&b.name));
+
+  attrs
+}
+
+fn attr_kind(value: &str) -> &'static str {
+  if value.parse::<i64>().is_ok() {
+    "int"
+  } else if value.parse::<f64>().is_ok() {
+    "float"
+  } else if value.split_whitespace().count() > 1
+    && value
+      .split_whitespace()
+      .all(|part| part.parse::<f64>().is_ok())
+  {
+    "list"
+  } else {
+    "string"
+  }
+}
@@ -1,37 +1,90 @@
+use super::IioOptions;
 use super::channel::{
-  ChannelConfig, axis_config_with_sample_type, channel_sample_type, read_axis_scaled,
-  read_first_sample_as_i64,
+  ChannelConfig, accel_axis_config, axis_config_with_sample_type, counter_delta, is_raw_saturated,
+  read_axis_raw, read_first_sample_as_i64, scalar_config, scale_axis, scale_raw,
+  sort_axis_by_scan_index, timestamp_config,
 };
+use super::preflight::unsupported_rate;
 use super::trigger::{
-  configure_sampling_frequency, disable_iio_buffer, is_device_busy_error, is_device_timeout_error,
-  select_trigger, set_trigger,
+  configure_sampling_frequency, configure_trigger_frequency, disable_iio_buffer,
+  is_device_busy_error, is_device_timeout_error, select_trigger, set_trigger,
 };
-use super::types::{AxisSet, DiscoveredDevice};
+use super::types::{AxisSet, DeviceInfo, DiscoveredDevice};
 use crate::error::{Error, Result};
+use crate::sensor::ImuSample;
 use industrial_io as iio;
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use std::fmt::Write as _;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
+const TRIGGER_INTERVAL_BUCKETS_US: [u64; 12] = [
+  50, 100, 200, 400, 800, 1_600, 3_200, 6_400, 12_800, 25_600, 51_200, 102_400,
+];
+
+#[derive(Debug)]
+pub struct JitterHistogram {
+  buckets: [AtomicU64; TRIGGER_INTERVAL_BUCKETS_US.len() + 1],
+  count: AtomicU64,
+  sum_us: AtomicU64,
+  max_us: AtomicU64,
+  report_every: u64,
+}
+
+// Variances are expressed as fractions of the nominal period so the same
+// tuning holds across sample rates.
+const DT_PROCESS_NOISE: f64 = 0.01;
+const DT_HARDWARE_NOISE: f64 = 0.05;
+const DT_NOMINAL_NOISE: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy)]
+struct DtKalman {
+  q_process: f64,
+  r_measurement: f64,
+  x_est: f64,
+  p_est: f64,
+}
+
 #[derive(Debug)]
 pub struct BufferPoller {
   buffer: iio::Buffer,
   accel: AxisSet<ChannelConfig>,
   gyro: Option<AxisSet<ChannelConfig>>,
   timestamp: Option<ChannelConfig>,
+  temp: Option<ChannelConfig>,
   sysfs_trigger: Option<iio::Device>,
   sysfs_trigger_period: Duration,
   sysfs_trigger_last_fire: Option<Instant>,
+  trigger_name: Option<String>,
+  sample_timeout: Option<(RawFd, Duration)>,
+  originally_enabled: Vec<iio::Channel>,
+  enabled_by_us: Vec<iio::Channel>,
+  trigger_jitter: Option<Arc<JitterHistogram>>,
+  dt_kalman: Option<DtKalman>,
+  reject_nonfinite: bool,
+  nonfinite_samples: Option<Arc<AtomicU64>>,
 }
 
 impl BufferPoller {
-  pub fn new(ctx: &iio::Context, discovered: &DiscoveredDevice, rate_hz: u32) -> Result<Self> {
+  pub fn new(
+    ctx: &iio::Context,
+    discovered: &DiscoveredDevice,
+    options: &IioOptions,
+  ) -> Result<Self> {
+    let skip_rate_config = options.rate_hz_override.is_some();
+    let rate_hz = options.rate_hz_override.unwrap_or(options.rate_hz);
+
     if !discovered.dev.is_buffer_capable() {
       return Err(Error::invalid_state("device is not buffer capable"));
     }
 
-    let accel = axis_config_with_sample_type(&discovered.accel)?;
-    let gyro = discovered
-      .gyro
+    let (accel_chans, gyro_chans) = axis_channels(discovered, options.sort_axes_by_scan_index)?;
+    let accel = accel_axis_config(&accel_chans, options.accel_implicit_scale)?;
+    let gyro = gyro_chans
       .as_ref()
       .map(axis_config_with_sample_type)
       .transpose()?;
@@ -39,14 +92,13 @@ impl BufferPoller {
       .timestamp
       .as_ref()
       .filter(|chan| chan.is_scan_element())
-      .map(|chan| -> Result<ChannelConfig> {
-        Ok(ChannelConfig {
-          chan: chan.clone(),
-          scale: 1.0,
-          offset: 0,
-          sample_type: Some(channel_sample_type(chan)?),
-        })
-      })
+      .map(timestamp_config)
+      .transpose()?;
+    let temp = discovered
+      .temp
+      .as_ref()
+      .filter(|chan| chan.is_scan_element())
+      .map(scalar_config)
       .transpose()?;
 
     let mut enable = Vec::new();
@@ -61,34 +113,41 @@ impl BufferPoller {
       enable.push(ts);
     }
 
-    let scan_inputs: Vec<iio::Channel> = discovered
-      .dev
-      .channels()
-      .filter(|chan| chan.is_scan_element() && chan.is_input())
-      .collect();
-
-    for chan in &scan_inputs {
-      chan.disable();
-    }
-
-    for chan in &enable {
-      chan.chan.enable();
+    if let Some(temp) = temp.as_ref() {
+      enable.push(temp);
     }
 
-    let enabled_scan_inputs = scan_inputs.iter().filter(|chan| chan.is_enabled()).count();
+    let (enabled_scan_inputs, originally_enabled, enabled_by_us) =
+      enable_scan_channels(&discovered.dev, &enable);
     let triggers: Vec<iio::Device> = ctx.devices().filter(iio::Device::is_trigger).collect();
 
     if triggers.is_empty() {
       return Err(Error::IioTriggerNotFound);
     }
 
-    let trigger = select_trigger(&triggers);
-
-    configure_sampling_frequency(&discovered.dev, trigger.as_ref(), &enable, rate_hz)?;
+    let trigger = select_trigger(
+      &triggers,
+      options.trigger_name.as_deref(),
+      options.trigger_name_exact,
+    );
+
+    if skip_rate_config {
+      eprintln!(
+        "warning: --rate-hz-override set, leaving the device sampling_frequency alone and assuming {rate_hz}hz"
+      );
+      configure_trigger_frequency(&discovered.dev, trigger.as_ref(), rate_hz)?;
+    } else {
+      configure_sampling_frequency(&discovered.dev, trigger.as_ref(), &enable, rate_hz)?;
+    }
 
-    let trigger_name = set_trigger(&discovered.dev, trigger.as_ref())?;
+    let trigger_name = set_trigger(&discovered.dev, trigger.as_ref(), options.verbose_iio)?;
 
     eprintln!("iio trigger: {trigger_name:?}");
+    log_axis_calibration("accel", &accel);
+
+    if let Some(gyro) = gyro.as_ref() {
+      log_axis_calibration("gyro", gyro);
+    }
 
     if enabled_scan_inputs == 0 {
       return Err(Error::invalid_state(
@@ -96,39 +155,22 @@ impl BufferPoller {
       ));
     }
 
-    let buffer = match discovered.dev.create_buffer(1, false) {
-      Ok(buffer) => buffer,
-      Err(err) if is_device_busy_error(&err) => {
-        disable_iio_buffer(&discovered.dev)?;
-
-        match discovered.dev.create_buffer(1, false) {
-          Ok(buffer) => buffer,
-          Err(err) if is_device_busy_error(&err) => return Err(err.into()),
-          Err(err) => {
-            let sample_size = discovered.dev.sample_size().ok();
-
-            return Err(Error::invalid_state(format!(
-              "failed to create iio buffer: {err} (device={:?} name={:?} trigger={:?} enabled_scan_inputs={enabled_scan_inputs} sample_size={sample_size:?})",
-              discovered.dev.id(),
-              discovered.dev.name(),
-              trigger_name.as_ref(),
-            )));
-          }
-        }
-      }
+    let buffer = create_buffer(
+      &discovered.dev,
+      trigger_name.as_deref(),
+      enabled_scan_inputs,
+    )?;
 
-      Err(err) => {
-        let sample_size = discovered.dev.sample_size().ok();
-
-        return Err(Error::invalid_state(format!(
-          "failed to create iio buffer: {err} (device={:?} name={:?} trigger={:?} enabled_scan_inputs={enabled_scan_inputs} sample_size={sample_size:?})",
-          discovered.dev.id(),
-          discovered.dev.name(),
-          trigger_name.as_ref(),
-        )));
-      }
-    };
+    let sample_timeout = options
+      .sample_timeout
+      .and_then(|timeout| match buffer.poll_fd() {
+        Ok(fd) => Some((fd, timeout)),
+        Err(err) => {
+          eprintln!("iio buffer has no poll fd, relying on context timeout only: {err}");
 
+          None
+        }
+      });
     let sysfs_trigger = trigger
       .as_ref()
       .filter(|trigger| trigger.has_attr("trigger_now"))
@@ -139,12 +181,74 @@ impl BufferPoller {
       accel,
       gyro,
       timestamp,
+      temp,
       sysfs_trigger,
       sysfs_trigger_period: Duration::from_nanos((1_000_000_000_u64 / u64::from(rate_hz)).max(1)),
       sysfs_trigger_last_fire: None,
+      trigger_name,
+      sample_timeout,
+      originally_enabled,
+      enabled_by_us,
+      trigger_jitter: options.trigger_jitter.clone(),
+      dt_kalman: options.dt_kalman.then(|| DtKalman::new(rate_hz)),
+      reject_nonfinite: options.reject_nonfinite,
+      nonfinite_samples: options.nonfinite_samples.clone(),
     })
   }
 
+  pub fn unsupported_rate(&self, rate_hz: u32) -> Option<String> {
+    unsupported_rate(self.buffer.device(), rate_hz)
+  }
+
+  pub fn device_id(&self) -> Option<String> {
+    self.buffer.device().id()
+  }
+
+  pub fn describe(&self) -> String {
+    let dev = self.buffer.device();
+
+    format!(
+      "iio device={:?} name={:?} trigger={:?} gyro={} timestamp={}",
+      dev.id(),
+      dev.name(),
+      self.trigger_name,
+      self.gyro.is_some(),
+      self.timestamp.is_some(),
+    )
+  }
+
+  pub fn device_info(&self) -> DeviceInfo {
+    let dev = self.buffer.device();
+
+    DeviceInfo {
+      id: dev.id(),
+      name: dev.name(),
+      firmware_version: dev
+        .attr_read_str("firmware_version")
+        .ok()
+        .map(|version| version.trim().to_string()),
+      fifo_watermark_max: dev
+        .attr_read_int("buffer/hwfifo_watermark_max")
+        .ok()
+        .and_then(|max| u32::try_from(max).ok()),
+      sample_size: dev.sample_size().ok(),
+      accel_channels: self
+        .accel
+        .as_array_ref()
+        .iter()
+        .filter_map(|axis| {
+          let id = axis.chan.id()?;
+
+          Some(if axis.chan.is_input() {
+            format!("in_{id}")
+          } else {
+            format!("out_{id}")
+          })
+        })
+        .collect(),
+    }
+  }
+
   fn maybe_fire_sysfs_trigger(&mut self) -> Result<()> {
     let Some(trigger) = self.sysfs_trigger.as_ref() else {
       return Ok(());
@@ -161,7 +265,18 @@ impl BufferPoller {
     }
 
     trigger.attr_write_int("trigger_now", 1)?;
-    self.sysfs_trigger_last_fire = Some(Instant::now());
+
+    let fired = Instant::now();
+
+    if let Some((jitter, last_fire)) = self
+      .trigger_jitter
+      .as_ref()
+      .zip(self.sysfs_trigger_last_fire)
+    {
+      jitter.record(fired.duration_since(last_fire));
+    }
+
+    self.sysfs_trigger_last_fire = Some(fired);
     Ok(())
   }
 
@@ -169,23 +284,69 @@ impl BufferPoller {
     &mut self,
     rate_hz: u32,
     last_timestamp_ns: &mut Option<i64>,
-  ) -> Result<super::ImuSample> {
-    self.maybe_fire_sysfs_trigger()?;
+  ) -> Result<ImuSample> {
+    loop {
+      let prev_timestamp_ns = *last_timestamp_ns;
+      let sample = self.read_scan(rate_hz, last_timestamp_ns)?;
 
-    match self.buffer.refill() {
-      Ok(_len) => {}
+      if is_valid_accel(sample.accel_mps2) {
+        return Ok(sample);
+      }
+
+      if let Some(counter) = self.nonfinite_samples.as_ref() {
+        counter.fetch_add(1, Ordering::Relaxed);
+      }
+
+      if !self.reject_nonfinite {
+        return Err(Error::invalid_state("non-finite accel sample"));
+      }
 
-      Err(err) if is_device_timeout_error(&err) => {
-        let dev = self.buffer.device();
+      // The next sample's dt should span the one dropped here.
+      *last_timestamp_ns = prev_timestamp_ns;
+    }
+  }
+
+  // Stale FIFO contents from before the buffer was enabled are read and
+  // dropped; timeouts and EBUSY while the driver settles get extra attempts.
+  pub fn warm_up(
+    &mut self,
+    reads: u32,
+    rate_hz: u32,
+    last_timestamp_ns: &mut Option<i64>,
+  ) -> Result<()> {
+    if reads == 0 {
+      return Ok(());
+    }
 
-        return Err(Error::invalid_state(format!(
-          "iio buffer refill timed out (device={:?} name={:?} sysfs_trigger_now={} rate_hz={rate_hz})",
-          dev.id(),
-          dev.name(),
-          self.sysfs_trigger.is_some(),
-        )));
+    let mut done = 0;
+    let mut retries = 0;
+
+    while done < reads {
+      match self.read_sample(rate_hz, last_timestamp_ns) {
+        Ok(_) => done += 1,
+        Err(Error::IioTimeout(_)) if retries < reads => retries += 1,
+        Err(Error::Iio(err)) if retries < reads && is_device_busy_error(&err) => retries += 1,
+        Err(err) => return Err(err),
       }
+    }
 
+    eprintln!("IIO warmup done after {} reads", done + retries);
+
+    Ok(())
+  }
+
+  fn read_scan(&mut self, rate_hz: u32, last_timestamp_ns: &mut Option<i64>) -> Result<ImuSample> {
+    self.maybe_fire_sysfs_trigger()?;
+
+    if let Some((fd, timeout)) = self.sample_timeout
+      && !wait_readable(fd, timeout)?
+    {
+      return Err(self.timeout_error(rate_hz));
+    }
+
+    match self.buffer.refill() {
+      Ok(_len) => {}
+      Err(err) if is_device_timeout_error(&err) => return Err(self.timeout_error(rate_hz)),
       Err(err) => return Err(err.into()),
     }
 
@@ -195,40 +356,309 @@ impl BufferPoller {
       .map(|ts| read_first_sample_as_i64(&self.buffer, ts))
       .transpose()?;
 
-    let dt_sec = match timestamp_ns {
-      Some(ts) => {
-        let dt_ns = last_timestamp_ns.and_then(|prev| ts.checked_sub(prev));
-        *last_timestamp_ns = Some(ts);
-
-        match dt_ns {
-          Some(dt_ns) if dt_ns > 0 => {
-            let dt_ns = u64::try_from(dt_ns).unwrap_or(0);
-
-            if dt_ns == 0 {
-              1.0 / f64::from(rate_hz)
-            } else {
-              Duration::from_nanos(dt_ns).as_secs_f64()
-            }
-          }
-
-          _ => 1.0 / f64::from(rate_hz),
-        }
-      }
-
-      None => 1.0 / f64::from(rate_hz),
+    let timestamp_bits = self.timestamp.as_ref().map_or(64, |ts| ts.bits);
+    let hardware_dt_sec = timestamp_ns.and_then(|ts| {
+      let dt_ns = last_timestamp_ns.and_then(|prev| counter_delta(prev, ts, timestamp_bits));
+      *last_timestamp_ns = Some(ts);
+
+      dt_ns
+        .and_then(|dt_ns| u64::try_from(dt_ns).ok())
+        .filter(|&dt_ns| dt_ns > 0)
+        .map(|dt_ns| Duration::from_nanos(dt_ns).as_secs_f64())
+    });
+    let nominal_dt_sec = 1.0 / f64::from(rate_hz);
+    let dt_sec = match self.dt_kalman.as_mut() {
+      Some(kalman) => kalman.update(nominal_dt_sec, hardware_dt_sec),
+      None => hardware_dt_sec.unwrap_or(nominal_dt_sec),
     };
 
-    let accel_mps2 = read_axis_scaled(&self.buffer, &self.accel)?;
-    let gyro = self
+    let accel_raw = read_axis_raw(&self.buffer, &self.accel)?;
+    let accel_mps2 = scale_axis(accel_raw, &self.accel)?;
+    let gyro_raw = self
       .gyro
       .as_ref()
-      .map(|gyro| read_axis_scaled(&self.buffer, gyro))
+      .map(|gyro| read_axis_raw(&self.buffer, gyro))
       .transpose()?;
+    let gyro = match (gyro_raw, self.gyro.as_ref()) {
+      (Some(raw), Some(axis)) => Some(scale_axis(raw, axis)?),
+      _ => None,
+    };
 
-    Ok(super::ImuSample {
+    let temp_c = self
+      .temp
+      .as_ref()
+      .map(|temp| scale_raw(read_first_sample_as_i64(&self.buffer, temp)?, temp))
+      .transpose()?
+      .map(|milli_c| milli_c / 1000.0);
+
+    Ok(ImuSample {
       accel_mps2,
       gyro,
       dt_sec,
+      mag_ut: None,
+      temp_c,
+      accel_raw: Some(accel_raw),
+      gyro_raw,
+      accel_saturated: accel_raw
+        .iter()
+        .zip(self.accel.as_array_ref())
+        .any(|(&raw, cfg)| is_raw_saturated(raw, cfg)),
     })
   }
 }
+
+impl BufferPoller {
+  pub fn timeout_error(&self, rate_hz: u32) -> Error {
+    let dev = self.buffer.device();
+
+    Error::IioTimeout(format!(
+      "device={:?} name={:?} sysfs_trigger_now={} rate_hz={rate_hz}",
+      dev.id(),
+      dev.name(),
+      self.sysfs_trigger.is_some(),
+    ))
+  }
+}
+
+impl DtKalman {
+  fn new(rate_hz: u32) -> Self {
+    let period = 1.0 / f64::from(rate_hz.max(1));
+
+    Self {
+      q_process: (DT_PROCESS_NOISE * period).powi(2),
+      r_measurement: (DT_HARDWARE_NOISE * period).powi(2),
+      x_est: period,
+      p_est: (DT_NOMINAL_NOISE * period).powi(2),
+    }
+  }
+
+  // The nominal period is always observed with a wide variance; a hardware
+  // delta, when present, is fused on top with the tight one.
+  fn update(&mut self, nominal_dt_sec: f64, hardware_dt_sec: Option<f64>) -> f64 {
+    self.p_est += self.q_process;
+    self.correct(nominal_dt_sec, (DT_NOMINAL_NOISE * nominal_dt_sec).powi(2));
+
+    if let Some(dt) = hardware_dt_sec.filter(|dt| dt.is_finite()) {
+      self.correct(dt, self.r_measurement);
+    }
+
+    self.x_est
+  }
+
+  fn correct(&mut self, measurement: f64, variance: f64) {
+    let gain = self.p_est / (self.p_est + variance);
+
+    self.x_est += gain * (measurement - self.x_est);
+    self.p_est *= 1.0 - gain;
+  }
+}
+
+impl JitterHistogram {
+  pub fn new(report_every: u64) -> Self {
+    Self {
+      buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+      count: AtomicU64::new(0),
+      sum_us: AtomicU64::new(0),
+      max_us: AtomicU64::new(0),
+      report_every: report_every.max(1),
+    }
+  }
+
+  pub fn record(&self, interval: Duration) {
+    let us = u64::try_from(interval.as_micros()).unwrap_or(u64::MAX);
+    let bucket = TRIGGER_INTERVAL_BUCKETS_US
+      .iter()
+      .position(|&le| us <= le)
+      .unwrap_or(TRIGGER_INTERVAL_BUCKETS_US.len());
+
+    self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    self.sum_us.fetch_add(us, Ordering::Relaxed);
+    self.max_us.fetch_max(us, Ordering::Relaxed);
+
+    let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if count.is_multiple_of(self.report_every) {
+      eprintln!("{}", self.summary());
+    }
+  }
+
+  pub fn render(&self, out: &mut String) {
+    let name = "yured_trigger_interval_microseconds";
+    let mut cumulative = 0;
+
+    let _ = writeln!(out, "# HELP {name} interval between sysfs trigger fires");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+
+    for (le, bucket) in TRIGGER_INTERVAL_BUCKETS_US.iter().zip(&self.buckets) {
+      cumulative += bucket.load(Ordering::Relaxed);
+      let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+    }
+
+    let _ = writeln!(
+      out,
+      "{name}_bucket{{le=\"+Inf\"}} {}",
+      self.count.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(out, "{name}_sum {}", self.sum_us.load(Ordering::Relaxed));
+    let _ = writeln!(out, "{name}_count {}", self.count.load(Ordering::Relaxed));
+  }
+
+  fn summary(&self) -> String {
+    let count = self.count.load(Ordering::Relaxed).max(1);
+    let max = self.max_us.load(Ordering::Relaxed);
+
+    format!(
+      "trigger interval over {count} fires: mean={}us p50<={}us p99<={}us max={max}us",
+      self.sum_us.load(Ordering::Relaxed) / count,
+      self.quantile_bound(count, 50).min(max),
+      self.quantile_bound(count, 99).min(max),
+    )
+  }
+
+  // Upper bound of the bucket holding the quantile; the overflow bucket reports max.
+  fn quantile_bound(&self, count: u64, percent: u64) -> u64 {
+    let rank = count.saturating_mul(percent).div_ceil(100).max(1);
+    let mut cumulative = 0;
+
+    for (le, bucket) in TRIGGER_INTERVAL_BUCKETS_US.iter().zip(&self.buckets) {
+      cumulative += bucket.load(Ordering::Relaxed);
+
+      if cumulative >= rank {
+        return *le;
+      }
+    }
+
+    self.max_us.load(Ordering::Relaxed)
+  }
+}
+
+// Runs on a normal return, including SIGINT/SIGTERM through the shutdown
+// handler; after SIGKILL or a crash the next open has to cope with whatever
+// buffer and channel state was left behind.
+impl Drop for BufferPoller {
+  fn drop(&mut self) {
+    let dev = self.buffer.device();
+
+    if let Err(err) = disable_iio_buffer(dev) {
+      eprintln!("failed to disable iio buffer on drop: {err}");
+    }
+
+    for chan in &self.enabled_by_us {
+      chan.disable();
+    }
+
+    for chan in &self.originally_enabled {
+      chan.enable();
+    }
+  }
+}
+
+fn is_valid_accel(v: [f64; 3]) -> bool {
+  v.iter().all(|x| x.is_finite())
+}
+
+fn axis_channels(
+  discovered: &DiscoveredDevice,
+  sort_by_scan_index: bool,
+) -> Result<(AxisSet<iio::Channel>, Option<AxisSet<iio::Channel>>)> {
+  if !sort_by_scan_index {
+    return Ok((discovered.accel.clone(), discovered.gyro.clone()));
+  }
+
+  let accel = sort_axis_by_scan_index(&discovered.accel)?;
+  let gyro = discovered
+    .gyro
+    .as_ref()
+    .map(sort_axis_by_scan_index)
+    .transpose()?;
+
+  Ok((accel, gyro))
+}
+
+fn enable_scan_channels(
+  dev: &iio::Device,
+  enable: &[&ChannelConfig],
+) -> (usize, Vec<iio::Channel>, Vec<iio::Channel>) {
+  let scan_inputs: Vec<iio::Channel> = dev
+    .channels()
+    .filter(|chan| chan.is_scan_element() && chan.is_input())
+    .collect();
+  let originally_enabled: Vec<iio::Channel> = scan_inputs
+    .iter()
+    .filter(|chan| chan.is_enabled())
+    .cloned()
+    .collect();
+  let enabled_by_us: Vec<iio::Channel> = enable
+    .iter()
+    .filter(|config| !config.chan.is_enabled())
+    .map(|config| config.chan.clone())
+    .collect();
+
+  for chan in &scan_inputs {
+    chan.disable();
+  }
+
+  for chan in enable {
+    chan.chan.enable();
+  }
+
+  let enabled_scan_inputs = scan_inputs.iter().filter(|chan| chan.is_enabled()).count();
+
+  (enabled_scan_inputs, originally_enabled, enabled_by_us)
+}
+
+fn wait_readable(fd: RawFd, timeout: Duration) -> Result<bool> {
+  // SAFETY: the fd is owned by the iio buffer, which outlives this call.
+  let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+  let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+
+  loop {
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+
+    match poll(&mut fds, timeout) {
+      Ok(ready) => return Ok(ready > 0),
+      Err(Errno::EINTR) => {}
+      Err(err) => return Err(Error::invalid_state_with("failed to poll iio buffer", err)),
+    }
+  }
+}
+
+fn create_buffer(
+  dev: &iio::Device,
+  trigger_name: Option<&str>,
+  enabled_scan_inputs: usize,
+) -> Result<iio::Buffer> {
+  let err = match dev.create_buffer(1, false) {
+    Ok(buffer) => return Ok(buffer),
+    Err(err) if is_device_busy_error(&err) => {
+      disable_iio_buffer(dev)?;
+
+      match dev.create_buffer(1, false) {
+        Ok(buffer) => return Ok(buffer),
+        Err(err) if is_device_busy_error(&err) => return Err(err.into()),
+        Err(err) => err,
+      }
+    }
+
+    Err(err) => err,
+  };
+  let sample_size = dev.sample_size().ok();
+
+  Err(Error::invalid_state_with(
+    format!(
+      "failed to create iio buffer (device={:?} name={:?} trigger={trigger_name:?} enabled_scan_inputs={enabled_scan_inputs} sample_size={sample_size:?})",
+      dev.id(),
+      dev.name(),
+    ),
+    err,
+  ))
+}
+
+fn log_axis_calibration(kind: &str, axis: &AxisSet<ChannelConfig>) {
+  let [x, y, z] = axis.as_array_ref();
+
+  eprintln!(
+    "iio {kind}: scale=[{}, {}, {}] offset=[{}, {}, {}]",
+    x.scale, y.scale, z.scale, x.offset, y.offset, z.offset,
+  );
+}
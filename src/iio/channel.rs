@@ -1,9 +1,13 @@
 use super::types::AxisSet;
 use crate::error::{Error, Result};
 use industrial_io as iio;
+use serde::Serialize;
 use std::any::TypeId;
+use std::ops::RangeInclusive;
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+const ACCEL_FULL_SCALE_MPS2: RangeInclusive<f64> = 9.806_65..=4000.0;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
 pub enum SampleType {
   I8,
   I16,
@@ -15,12 +19,46 @@ pub enum SampleType {
   U64,
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RawFormat {
+  pub length: u32,
+  pub bits: u32,
+  pub shift: u32,
+  pub signed: bool,
+  pub big_endian: bool,
+}
+
+trait StorageBits: Copy + 'static {
+  fn storage_bits(self) -> u64;
+}
+
+macro_rules! impl_storage_bits {
+  ($($signed:ty => $unsigned:ty),*) => {
+    $(
+      impl StorageBits for $signed {
+        fn storage_bits(self) -> u64 {
+          u64::from(<$unsigned>::from_ne_bytes(self.to_ne_bytes()))
+        }
+      }
+
+      impl StorageBits for $unsigned {
+        fn storage_bits(self) -> u64 {
+          u64::from(self)
+        }
+      }
+    )*
+  };
+}
+
+impl_storage_bits!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+
 #[derive(Debug, Clone)]
 pub struct ChannelConfig {
   pub chan: iio::Channel,
   pub scale: f64,
   pub offset: i32,
   pub sample_type: Option<SampleType>,
+  pub bits: u32,
 }
 
 impl SampleType {
@@ -48,21 +86,101 @@ impl SampleType {
 }
 
 pub fn channel_scale(chan: &iio::Channel) -> Result<f64> {
+  try_channel_scale(chan)?.ok_or_else(|| Error::invalid_state("channel missing scale"))
+}
+
+fn try_channel_scale(chan: &iio::Channel) -> Result<Option<f64>> {
   let dfmt = chan.data_format();
 
   if chan.is_scan_element() {
     let scale = dfmt.scale();
 
     if scale != 0.0 {
-      return Ok(scale);
+      return validate_scale(chan, scale).map(Some);
     }
   }
 
   if !chan.has_attr("scale") {
-    return Err(Error::invalid_state("channel missing scale"));
+    return Ok(None);
   }
 
-  Ok(chan.attr_read_float("scale")?)
+  validate_scale(chan, chan.attr_read_float("scale")?).map(Some)
+}
+
+fn validate_scale(chan: &iio::Channel, scale: f64) -> Result<f64> {
+  if !scale.is_finite() {
+    return Err(Error::invalid_state(format!(
+      "channel {:?} reports a non-finite scale {scale}",
+      chan.id(),
+    )));
+  }
+
+  Ok(scale)
+}
+
+fn full_scale_counts(chan: &iio::Channel) -> f64 {
+  let dfmt = chan.data_format();
+  let bits = if dfmt.is_signed() {
+    dfmt.bits().saturating_sub(1)
+  } else {
+    dfmt.bits()
+  };
+
+  2_f64.powi(i32::try_from(bits).unwrap_or(i32::MAX))
+}
+
+fn check_accel_range(axis: &AxisSet<ChannelConfig>) {
+  for cfg in axis.as_array_ref() {
+    let counts = full_scale_counts(&cfg.chan);
+    let full_scale = (counts * cfg.scale).abs();
+
+    if !ACCEL_FULL_SCALE_MPS2.contains(&full_scale) {
+      eprintln!(
+        "warning: accel channel {:?} scale {} gives a full-scale range of {full_scale:.3} m/s^2, outside the plausible {:.1}..{:.1}",
+        cfg.chan.id(),
+        cfg.scale,
+        ACCEL_FULL_SCALE_MPS2.start(),
+        ACCEL_FULL_SCALE_MPS2.end(),
+      );
+    }
+
+    if f64::from(cfg.offset).abs() >= counts {
+      eprintln!(
+        "warning: accel channel {:?} offset {} exceeds its {counts} count range",
+        cfg.chan.id(),
+        cfg.offset,
+      );
+    }
+  }
+}
+
+fn estimate_scale_from_raw(axis: &AxisSet<iio::Channel>) -> Result<f64> {
+  let mut sum = 0.0;
+
+  for chan in axis.as_array_ref() {
+    if !chan.has_attr("raw") {
+      return Err(Error::invalid_state(
+        "channel missing scale and raw, cannot estimate scale",
+      ));
+    }
+
+    let raw = chan.attr_read_int("raw")?;
+    let raw = f64::from(
+      i32::try_from(raw).map_err(|_err| Error::invalid_state("raw value does not fit into i32"))?,
+    );
+
+    sum += raw * raw;
+  }
+
+  let magnitude = sum.sqrt();
+
+  if magnitude == 0.0 {
+    return Err(Error::invalid_state(
+      "channel missing scale and raw magnitude is zero, cannot estimate scale",
+    ));
+  }
+
+  Ok(9.806_65 / magnitude)
 }
 
 fn channel_offset(chan: &iio::Channel) -> Result<i32> {
@@ -86,38 +204,143 @@ pub fn channel_sample_type(chan: &iio::Channel) -> Result<SampleType> {
     .ok_or_else(|| Error::invalid_state("unsupported channel sample type"))
 }
 
+pub fn timestamp_config(chan: &iio::Channel) -> Result<ChannelConfig> {
+  Ok(ChannelConfig {
+    chan: chan.clone(),
+    scale: 1.0,
+    offset: 0,
+    sample_type: Some(channel_sample_type(chan)?),
+    bits: chan.data_format().bits(),
+  })
+}
+
+pub fn scalar_config(chan: &iio::Channel) -> Result<ChannelConfig> {
+  Ok(ChannelConfig {
+    chan: chan.clone(),
+    scale: try_channel_scale(chan)?.unwrap_or(1.0),
+    offset: channel_offset(chan)?,
+    sample_type: Some(channel_sample_type(chan)?),
+    bits: chan.data_format().bits(),
+  })
+}
+
+pub fn sort_axis_by_scan_index(axis: &AxisSet<iio::Channel>) -> Result<AxisSet<iio::Channel>> {
+  let [x, y, z] = axis.as_array_ref();
+  let mut indexed = [
+    (scan_index(x)?, x.clone()),
+    (scan_index(y)?, y.clone()),
+    (scan_index(z)?, z.clone()),
+  ];
+  let labelled = [indexed[0].0, indexed[1].0, indexed[2].0];
+
+  indexed.sort_by_key(|(index, _)| *index);
+
+  if !labelled.is_sorted() {
+    eprintln!(
+      "warning: axis scan indices x/y/z={labelled:?} are not ascending; reassigning axes by index, check the driver's axis labels"
+    );
+  }
+
+  let [(_, x), (_, y), (_, z)] = indexed;
+
+  Ok(AxisSet { x, y, z })
+}
+
+fn scan_index(chan: &iio::Channel) -> Result<usize> {
+  chan.index().map_err(|err| {
+    Error::invalid_state_with(format!("channel {:?} has no scan index", chan.id()), err)
+  })
+}
+
 pub fn axis_config_with_sample_type(
   axis: &AxisSet<iio::Channel>,
 ) -> Result<AxisSet<ChannelConfig>> {
+  let scales = [
+    channel_scale(&axis.x)?,
+    channel_scale(&axis.y)?,
+    channel_scale(&axis.z)?,
+  ];
+
+  axis_config(axis, scales)
+}
+
+pub fn accel_axis_config(
+  axis: &AxisSet<iio::Channel>,
+  implicit_scale: Option<f64>,
+) -> Result<AxisSet<ChannelConfig>> {
+  let [x, y, z] = axis.as_array_ref().map(try_channel_scale);
+  let found = [x?, y?, z?];
+
+  if found.iter().all(Option::is_some) {
+    let config = axis_config(axis, found.map(Option::unwrap_or_default))?;
+
+    check_accel_range(&config);
+
+    return Ok(config);
+  }
+
+  let fallback = if let Some(scale) = implicit_scale {
+    scale
+  } else {
+    let scale = estimate_scale_from_raw(axis)?;
+
+    eprintln!(
+      "warning: accel channels have no scale attribute, estimated {scale} from raw readings assuming the device is at rest"
+    );
+
+    scale
+  };
+
+  let config = axis_config(axis, found.map(|scale| scale.unwrap_or(fallback)))?;
+
+  check_accel_range(&config);
+
+  Ok(config)
+}
+
+fn axis_config(axis: &AxisSet<iio::Channel>, scales: [f64; 3]) -> Result<AxisSet<ChannelConfig>> {
   Ok(AxisSet {
     x: ChannelConfig {
-      scale: channel_scale(&axis.x)?,
+      scale: scales[0],
       offset: channel_offset(&axis.x)?,
       sample_type: Some(channel_sample_type(&axis.x)?),
+      bits: axis.x.data_format().bits(),
       chan: axis.x.clone(),
     },
     y: ChannelConfig {
-      scale: channel_scale(&axis.y)?,
+      scale: scales[1],
       offset: channel_offset(&axis.y)?,
       sample_type: Some(channel_sample_type(&axis.y)?),
+      bits: axis.y.data_format().bits(),
       chan: axis.y.clone(),
     },
     z: ChannelConfig {
-      scale: channel_scale(&axis.z)?,
+      scale: scales[2],
       offset: channel_offset(&axis.z)?,
       sample_type: Some(channel_sample_type(&axis.z)?),
+      bits: axis.z.data_format().bits(),
       chan: axis.z.clone(),
     },
   })
 }
 
-pub fn read_axis_scaled(buffer: &iio::Buffer, axis: &AxisSet<ChannelConfig>) -> Result<[f64; 3]> {
-  let [x, y, z] = axis.as_array_ref().map(|ch| read_first_scaled(buffer, ch));
+pub fn read_axis_raw(buffer: &iio::Buffer, axis: &AxisSet<ChannelConfig>) -> Result<[i64; 3]> {
+  let [x, y, z] = axis
+    .as_array_ref()
+    .map(|ch| read_first_sample_as_i64(buffer, ch));
   Ok([x?, y?, z?])
 }
 
-fn read_first_scaled(buffer: &iio::Buffer, cfg: &ChannelConfig) -> Result<f64> {
-  let raw = read_first_sample_as_i64(buffer, cfg)?;
+pub fn scale_axis(raw: [i64; 3], axis: &AxisSet<ChannelConfig>) -> Result<[f64; 3]> {
+  let [x, y, z] = axis.as_array_ref();
+  Ok([
+    scale_raw(raw[0], x)?,
+    scale_raw(raw[1], y)?,
+    scale_raw(raw[2], z)?,
+  ])
+}
+
+pub fn scale_raw(raw: i64, cfg: &ChannelConfig) -> Result<f64> {
   let raw = raw
     .try_into()
     .map_err(|_err| Error::invalid_state("sample does not fit into i32"))?;
@@ -142,24 +365,182 @@ pub fn read_first_sample_as_i64(buffer: &iio::Buffer, cfg: &ChannelConfig) -> Re
   }
 }
 
-fn read_first<T>(
+// `Channel::convert` is a silent no-op when the requested type does not match
+// `type_of`, so shift and sign extension are applied here from the data format.
+fn read_first<T: StorageBits>(
   buffer: &iio::Buffer,
   chan: &iio::Channel,
   iter: for<'a> fn(&'a iio::Buffer, &'a iio::Channel) -> iio::buffer::Iter<'a, T>,
-) -> Result<i64>
-where
-  T: Copy + 'static,
-  i64: TryFrom<T>,
-{
+) -> Result<i64> {
   let value = iter(buffer, chan)
     .next()
     .copied()
     .ok_or_else(|| Error::invalid_state("missing sample"))?;
-  let converted = chan.convert(value);
+  let format = chan.data_format();
 
-  i64::try_from(converted).map_err(|_err| Error::invalid_state("invalid sample value"))
+  decode_raw(
+    value.storage_bits(),
+    RawFormat {
+      length: format.length(),
+      bits: format.bits(),
+      shift: format.shift(),
+      signed: format.is_signed(),
+      big_endian: format.is_big_endian(),
+    },
+  )
+}
+
+pub fn decode_raw(storage: u64, format: RawFormat) -> Result<i64> {
+  let RawFormat {
+    length,
+    bits,
+    shift,
+    signed,
+    big_endian,
+  } = format;
+
+  if !(1..=64).contains(&length) || bits == 0 || bits + shift > length {
+    return Err(Error::invalid_state_with(
+      "invalid channel data format",
+      format!("length={length} bits={bits} shift={shift}"),
+    ));
+  }
+
+  let mut value = storage;
+
+  if big_endian != cfg!(target_endian = "big") && length > 8 {
+    value = value.swap_bytes() >> (64 - length);
+  }
+
+  value >>= shift;
+
+  if bits < 64 {
+    let mask = (1_u64 << bits) - 1;
+
+    value &= mask;
+
+    if signed && value & (1 << (bits - 1)) != 0 {
+      value |= !mask;
+    }
+  }
+
+  if !signed && value > i64::MAX as u64 {
+    return Err(Error::invalid_state("invalid sample value"));
+  }
+
+  Ok(i64::from_ne_bytes(value.to_ne_bytes()))
+}
+
+// Narrow hardware counters wrap; the delta is taken modulo 2^bits so a wrap
+// reads as a small forward step instead of a huge backward jump.
+pub fn counter_delta(prev: i64, next: i64, bits: u32) -> Option<i64> {
+  if bits == 0 || bits >= 64 {
+    return next.checked_sub(prev);
+  }
+
+  let mask = (1_u64 << bits) - 1;
+  let delta = next.wrapping_sub(prev).cast_unsigned() & mask;
+
+  i64::try_from(delta).ok()
+}
+
+// A reading pinned at either end of the channel's raw range has clipped,
+// whatever the scale turns it into.
+pub fn is_raw_saturated(raw: i64, cfg: &ChannelConfig) -> bool {
+  let (min, max) = raw_limits(cfg.bits, cfg.chan.data_format().is_signed());
+
+  raw <= min || raw >= max
+}
+
+fn raw_limits(bits: u32, signed: bool) -> (i64, i64) {
+  let bits = bits.clamp(1, 63);
+
+  if signed {
+    let half = 1_i64 << (bits - 1);
+
+    (-half, half - 1)
+  } else {
+    (0, (1_i64 << bits) - 1)
+  }
 }
 
 pub fn apply_scale_offset(raw: i32, offset: i32, scale: f64) -> f64 {
   (f64::from(raw) + f64::from(offset)) * scale
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{RawFormat, apply_scale_offset, counter_delta, decode_raw, raw_limits};
+
+  const fn format(length: u32, bits: u32, shift: u32, signed: bool) -> RawFormat {
+    RawFormat {
+      length,
+      bits,
+      shift,
+      signed,
+      big_endian: cfg!(target_endian = "big"),
+    }
+  }
+
+  #[test]
+  fn shifted_signed_sample_matches_abi() {
+    // le:s12/16>>4, raw -100 stored in the upper 12 bits with junk in the low nibble.
+    let storage = u64::from(((-100_i16) << 4).cast_unsigned() | 0x000f);
+    let raw = decode_raw(storage, format(16, 12, 4, true)).unwrap();
+
+    assert_eq!(raw, -100);
+    assert!((apply_scale_offset(i32::try_from(raw).unwrap(), 0, 0.009_576) + 0.9576).abs() < 1e-9);
+  }
+
+  #[test]
+  fn shifted_unsigned_sample_masks_padding() {
+    // le:u10/16>>2 with the two top padding bits set.
+    let storage = (0b11 << 14) | (700 << 2);
+    let raw = decode_raw(storage, format(16, 10, 2, false)).unwrap();
+
+    assert_eq!(raw, 700);
+    assert!((apply_scale_offset(700, -512, 0.5) - 94.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn unshifted_full_width_sample_is_unchanged() {
+    let storage = u64::from((-12_345_i32).cast_unsigned());
+
+    assert_eq!(
+      decode_raw(storage, format(32, 32, 0, true)).unwrap(),
+      -12_345
+    );
+  }
+
+  #[test]
+  fn big_endian_sample_is_swapped() {
+    let storage = u64::from(u16::from_ne_bytes(0x0123_u16.to_be_bytes()));
+    let mut fmt = format(16, 16, 0, false);
+    fmt.big_endian = true;
+
+    assert_eq!(decode_raw(storage, fmt).unwrap(), 0x0123);
+  }
+
+  #[test]
+  fn narrow_counter_wraps_forward() {
+    assert_eq!(counter_delta(0xffff_fff0, 0x10, 32), Some(0x20));
+    assert_eq!(
+      counter_delta(i64::from(i32::MAX), i64::from(i32::MIN), 32),
+      Some(1)
+    );
+    assert_eq!(counter_delta(100, 250, 64), Some(150));
+    assert_eq!(counter_delta(250, 100, 64), Some(-150));
+  }
+
+  #[test]
+  fn raw_limits_cover_the_full_range() {
+    assert_eq!(raw_limits(16, true), (-32_768, 32_767));
+    assert_eq!(raw_limits(12, false), (0, 4095));
+    assert_eq!(raw_limits(64, true), (i64::MIN / 2, i64::MAX / 2));
+  }
+
+  #[test]
+  fn rejects_inconsistent_format() {
+    assert!(decode_raw(0, format(16, 14, 4, true)).is_err());
+  }
+}
@@ -1,8 +1,48 @@
-use super::types::{AxisSet, DiscoveredDevice};
+use super::TimestampSource;
+use super::channel::{channel_sample_type, channel_scale};
+use super::types::{AxisSet, DeviceSummary, DiscoveredDevice};
 use crate::error::{Error, Result};
 use industrial_io as iio;
+use std::fs;
+use std::path::Path;
 
-pub fn discover_best_device(ctx: &iio::Context) -> Result<DiscoveredDevice> {
+const UNBOUND_DEVICE_BUSES: [&str; 2] = ["/sys/bus/i2c/devices", "/sys/bus/spi/devices"];
+const HID_ACCEL_DEVICE: &str = "accel_3d";
+const HID_GYRO_DEVICE: &str = "gyro_3d";
+// Keys are part-number prefixes narrow enough to pick one driver; parts that
+// match none get no suggestion rather than a guess.
+const SENSOR_MODULES: &[(&str, &str)] = &[
+  ("bmi088", "bmi088_accel"),
+  ("bmi160", "bmi160_i2c"),
+  ("bmi270", "bmi270_i2c"),
+  ("bmi323", "bmi323_i2c"),
+  ("bmg160", "bmg160_i2c"),
+  ("icm426", "inv_icm42600_i2c"),
+  ("icm206", "inv_mpu6050_i2c"),
+  ("iam20680", "inv_mpu6050_i2c"),
+  ("mpu6", "inv_mpu6050_i2c"),
+  ("mpu9", "inv_mpu6050_i2c"),
+  ("mpu3050", "mpu3050"),
+  ("lsm6", "st_lsm6dsx_i2c"),
+  ("ism330", "st_lsm6dsx_i2c"),
+  ("lis3dh", "st_accel_i2c"),
+  ("lis331", "st_accel_i2c"),
+  ("lis2dh", "st_accel_i2c"),
+  ("adxl345", "adxl345_i2c"),
+  ("mma8451", "mma8452"),
+  ("mma8452", "mma8452"),
+  ("mma8453", "mma8452"),
+  ("mma8652", "mma8452"),
+  ("mma8653", "mma8452"),
+  ("accel_3d", "hid_sensor_accel_3d"),
+  ("gyro_3d", "hid_sensor_gyro_3d"),
+];
+
+pub fn discover_best_device(
+  ctx: &iio::Context,
+  timestamp_source: &TimestampSource,
+  device: Option<&str>,
+) -> Result<DiscoveredDevice> {
   let mut best_accel_only: Option<DiscoveredDevice> = None;
   let mut best_with_gyro: Option<DiscoveredDevice> = None;
   let mut best_with_gyro_timestamp: Option<DiscoveredDevice> = None;
@@ -13,6 +53,13 @@ pub fn discover_best_device(ctx: &iio::Context) -> Result<DiscoveredDevice> {
       continue;
     }
 
+    if let Some(want) = device
+      && dev.id().as_deref() != Some(want)
+      && dev.name().as_deref() != Some(want)
+    {
+      continue;
+    }
+
     let Some(accel) = find_axis_channels(&dev, &["accel", "in_accel"]) else {
       continue;
     };
@@ -30,7 +77,12 @@ pub fn discover_best_device(ctx: &iio::Context) -> Result<DiscoveredDevice> {
     }
 
     let gyro = find_axis_channels(&dev, &["anglvel", "in_anglvel"]);
-    let timestamp = dev.find_input_channel("timestamp");
+    let timestamp = match timestamp_source {
+      TimestampSource::Auto => find_timestamp_channel(&dev),
+      TimestampSource::Channel(id) => dev.find_input_channel(id),
+      TimestampSource::Disabled => None,
+    };
+    let temp = dev.find_input_channel("temp");
     let has_gyro = gyro.is_some();
     let has_timestamp = timestamp.is_some();
     let candidate = DiscoveredDevice {
@@ -38,6 +90,7 @@ pub fn discover_best_device(ctx: &iio::Context) -> Result<DiscoveredDevice> {
       accel,
       gyro,
       timestamp,
+      temp,
     };
 
     match (has_gyro, has_timestamp) {
@@ -62,14 +115,163 @@ pub fn discover_best_device(ctx: &iio::Context) -> Result<DiscoveredDevice> {
     .or(best_with_gyro)
     .or(best_accel_only)
   {
-    Some(device) => Ok(device),
+    Some(device) => {
+      validate_timestamp(&device, timestamp_source)?;
+      warn_hid_gyro(ctx, &device);
+
+      Ok(device)
+    }
     None if saw_accel_without_scan_elements => Err(Error::invalid_state(
       "accel device found, but scan-elements/buffer are not available",
     )),
-    None => Err(Error::SensorNotFound),
+    None => match device {
+      Some(want) => Err(Error::invalid_state(format!(
+        "iio device {want:?} not found or has no buffered accel channels"
+      ))),
+      None => Err(Error::SensorNotFound(suggest_modules_for_sensor(ctx))),
+    },
   }
 }
 
+// Lists every non-trigger device with accel channels, including ones discovery
+// would skip, and marks the device it would pick with these options.
+pub fn list_devices(
+  ctx: &iio::Context,
+  timestamp_source: &TimestampSource,
+  device: Option<&str>,
+) -> Vec<DeviceSummary> {
+  let selected = discover_best_device(ctx, timestamp_source, device)
+    .ok()
+    .and_then(|discovered| discovered.dev.id());
+
+  ctx
+    .devices()
+    .filter(|dev| !dev.is_trigger())
+    .filter_map(|dev| {
+      let accel = find_axis_channels(&dev, &["accel", "in_accel"])?;
+      let accel = accel.as_array_ref();
+      let id = dev.id();
+
+      Some(DeviceSummary {
+        is_selected: id.is_some() && id == selected,
+        id,
+        name: dev.name(),
+        has_gyro: find_axis_channels(&dev, &["anglvel", "in_anglvel"]).is_some(),
+        has_timestamp: find_timestamp_channel(&dev).is_some(),
+        accel_channels: accel
+          .iter()
+          .filter_map(|chan| chan.id())
+          .map(|id| format!("in_{id}"))
+          .collect(),
+        sample_types: accel
+          .iter()
+          .map(|chan| channel_sample_type(chan).ok())
+          .collect(),
+        scales: accel.iter().map(|chan| channel_scale(chan).ok()).collect(),
+      })
+    })
+    .collect()
+}
+
+fn warn_hid_gyro(ctx: &iio::Context, device: &DiscoveredDevice) {
+  if device.gyro.is_some() || device.dev.name().as_deref() != Some(HID_ACCEL_DEVICE) {
+    return;
+  }
+
+  if ctx
+    .devices()
+    .any(|dev| dev.name().as_deref() == Some(HID_GYRO_DEVICE))
+  {
+    eprintln!(
+      "hid sensor hub exposes gyro as a separate {HID_GYRO_DEVICE} device; streaming {HID_ACCEL_DEVICE} without gyro fusion"
+    );
+  }
+}
+
+// Devices whose driver is bound already show up in the context, so only i2c
+// and spi devices without a driver point at a module worth loading. Local
+// sysfs says nothing about a remote context.
+pub fn suggest_modules_for_sensor(ctx: &iio::Context) -> Vec<String> {
+  if ctx.name() != "local" {
+    return Vec::new();
+  }
+
+  let mut modules = Vec::new();
+
+  for bus in UNBOUND_DEVICE_BUSES {
+    let Ok(entries) = fs::read_dir(bus) else {
+      continue;
+    };
+
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+      if path.join("driver").exists() {
+        continue;
+      }
+
+      let Some(module) = unbound_device_name(&path).and_then(|name| module_for_device(&name))
+      else {
+        continue;
+      };
+
+      if !modules.iter().any(|known| known == module) {
+        modules.push(module.to_string());
+      }
+    }
+  }
+
+  modules
+}
+
+// i2c devices carry a name file; spi devices only a modalias like "spi:bmi160".
+fn unbound_device_name(path: &Path) -> Option<String> {
+  if let Ok(name) = fs::read_to_string(path.join("name")) {
+    return Some(name.trim().to_string());
+  }
+
+  let modalias = fs::read_to_string(path.join("modalias")).ok()?;
+
+  Some(modalias.trim().rsplit([':', ',']).next()?.to_string())
+}
+
+fn module_for_device(name: &str) -> Option<&'static str> {
+  let name = name.to_ascii_lowercase();
+
+  SENSOR_MODULES
+    .iter()
+    .find(|(prefix, _)| name.contains(prefix))
+    .map(|(_, module)| *module)
+}
+
+fn validate_timestamp(device: &DiscoveredDevice, timestamp_source: &TimestampSource) -> Result<()> {
+  let TimestampSource::Channel(id) = timestamp_source else {
+    return Ok(());
+  };
+
+  match device.timestamp.as_ref() {
+    Some(chan) if chan.is_scan_element() => Ok(()),
+    Some(_) => Err(Error::invalid_state(format!(
+      "timestamp channel {id:?} on device {:?} is not a scan element",
+      device.dev.name(),
+    ))),
+    None => Err(Error::invalid_state(format!(
+      "timestamp channel {id:?} not found on device {:?}",
+      device.dev.name(),
+    ))),
+  }
+}
+
+fn find_timestamp_channel(dev: &iio::Device) -> Option<iio::Channel> {
+  dev
+    .channels()
+    .filter(|chan| {
+      chan.is_input()
+        && chan
+          .id()
+          .is_some_and(|id| id.to_ascii_lowercase().contains("timestamp"))
+    })
+    .max_by_key(|chan| (chan.is_scan_element(), chan.data_format().bits()))
+}
+
 fn find_axis_channels(dev: &iio::Device, prefixes: &[&str]) -> Option<AxisSet<iio::Channel>> {
   let mut chans: [Option<iio::Channel>; 3] = [None, None, None];
 
@@ -111,3 +313,43 @@ pub fn axis_from_id(id: &str, prefixes: &[&str]) -> Option<usize> {
   }
   None
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{axis_from_id, module_for_device};
+
+  #[test]
+  fn libiio_channel_ids_map_to_axes() {
+    let axes: Vec<Option<usize>> = ["accel_x", "accel_y", "accel_z", "timestamp", "anglvel_x"]
+      .iter()
+      .map(|id| axis_from_id(id, &["accel", "in_accel"]))
+      .collect();
+
+    assert_eq!(axes, [Some(0), Some(1), Some(2), None, None]);
+    assert_eq!(
+      axis_from_id("anglvel_z", &["anglvel", "in_anglvel"]),
+      Some(2)
+    );
+  }
+
+  #[test]
+  fn unbound_device_names_map_to_modules() {
+    for (name, module) in [
+      ("bmi160", Some("bmi160_i2c")),
+      ("bmi323", Some("bmi323_i2c")),
+      ("MPU6050", Some("inv_mpu6050_i2c")),
+      ("mpu3050", Some("mpu3050")),
+      ("icm42688", Some("inv_icm42600_i2c")),
+      ("icm20602", Some("inv_mpu6050_i2c")),
+      ("icm20689", Some("inv_mpu6050_i2c")),
+      ("lis3dh", Some("st_accel_i2c")),
+      ("lis3mdl", None),
+      ("mma8450", None),
+      ("bmi085", None),
+      ("icm45686", None),
+      ("eeprom", None),
+    ] {
+      assert_eq!(module_for_device(name), module, "{name}");
+    }
+  }
+}
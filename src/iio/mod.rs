@@ -1,40 +1,130 @@
+mod attrs;
 mod buffer;
 mod channel;
 mod discovery;
+mod preflight;
 mod trigger;
 mod types;
 
+use self::attrs::enumerate_device_attrs;
 use self::buffer::BufferPoller;
-use self::discovery::discover_best_device;
-use self::trigger::{TriggerGuard, ensure_trigger_device};
+pub use self::buffer::JitterHistogram;
+use self::discovery::{discover_best_device, list_devices};
+pub use self::preflight::{preflight_issues, preflight_report};
+use self::trigger::{TriggerGuard, ensure_trigger_device, escalate, is_device_access_error};
+pub use self::types::{DeviceAttr, DeviceInfo, DeviceSummary};
 use crate::error::{Error, Result};
+use crate::sensor::{ImuSample, SensorPoller};
+use clap::ValueEnum;
 use industrial_io as iio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Copy)]
-pub struct ImuSample {
-  pub accel_mps2: [f64; 3],
-  pub gyro: Option<[f64; 3]>,
-  pub dt_sec: f64,
+const STANDARD_GRAVITY: f64 = 9.806_65;
+const ACCEL_UNIT_SPLIT: f64 = 3.0;
+// Nonzero samples whose median magnitude fixes --accel-unit auto.
+const ACCEL_UNIT_DETECT_SAMPLES: usize = 15;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum GyroUnit {
+  #[default]
+  Rad,
+  Deg,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum AccelUnit {
+  #[default]
+  Auto,
+  Mps2,
+  G,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum TimestampSource {
+  #[default]
+  Auto,
+  Channel(String),
+  Disabled,
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct IioOptions {
+  pub rate_hz: u32,
+  pub rate_hz_override: Option<u32>,
+  pub sample_timeout: Option<Duration>,
+  pub gyro_unit: GyroUnit,
+  pub accel_unit: AccelUnit,
+  pub timestamp: TimestampSource,
+  pub accel_implicit_scale: Option<f64>,
+  pub trigger_name: Option<String>,
+  pub trigger_name_exact: bool,
+  pub uri: Option<String>,
+  pub max_accel_mps2: f64,
+  pub clamp_accel: bool,
+  pub device: Option<String>,
+  pub trigger_jitter: Option<Arc<JitterHistogram>>,
+  pub dt_kalman: bool,
+  pub sort_axes_by_scan_index: bool,
+  pub verbose_iio: bool,
+  pub reject_nonfinite: bool,
+  pub warmup_reads: u32,
+  pub nonfinite_samples: Option<Arc<AtomicU64>>,
+  pub clamped_samples: Option<Arc<AtomicU64>>,
+  pub saturated_samples: Option<Arc<AtomicU64>>,
 }
 
 pub struct IioPoller {
   poller: BufferPoller,
   rate_hz: u32,
+  rate_locked: bool,
+  gyro_unit: GyroUnit,
+  accel_unit: AccelUnit,
+  unit_magnitudes: Vec<f64>,
+  gravity_checked: bool,
+  max_accel_mps2: f64,
+  clamp_accel: bool,
+  clamped_samples: Arc<AtomicU64>,
+  saturated_samples: Arc<AtomicU64>,
   last_timestamp_ns: Option<i64>,
   trigger_guard: Option<TriggerGuard>,
 }
 
 impl IioPoller {
-  pub fn open_best(rate_hz: u32) -> Result<Self> {
-    let ctx = iio::Context::with_backend(iio::Backend::Default)?;
+  pub fn open_best(options: &IioOptions) -> Result<Self> {
+    match Self::try_open_best(options) {
+      Err(Error::Iio(err)) if is_device_access_error(&err) => {
+        escalate("iio context access")?;
 
-    match Self::open_best_in_context(&ctx, rate_hz, None) {
+        Self::try_open_best(options)
+      }
+
+      result => result,
+    }
+  }
+
+  fn try_open_best(options: &IioOptions) -> Result<Self> {
+    let ctx = Self::open_context(options)?;
+
+    match Self::open_best_in_context(&ctx, options, None) {
       Ok(poller) => Ok(poller),
+      Err(Error::IioTriggerNotFound)
+        if options
+          .uri
+          .as_deref()
+          .is_some_and(|uri| !uri.starts_with("local:")) =>
+      {
+        Err(Error::invalid_state(
+          "no iio trigger devices found on the remote context; triggers can only be auto-created locally",
+        ))
+      }
       Err(Error::IioTriggerNotFound) => {
         let trigger_guard = ensure_trigger_device()?;
-        let ctx = iio::Context::with_backend(iio::Backend::Default)?;
+        let ctx = Self::open_context(options)?;
 
-        match Self::open_best_in_context(&ctx, rate_hz, trigger_guard) {
+        match Self::open_best_in_context(&ctx, options, trigger_guard) {
           Ok(poller) => Ok(poller),
           Err(Error::IioTriggerNotFound) => Err(Error::invalid_state(
             "no iio trigger devices found after attempting auto-creation",
@@ -47,27 +137,309 @@ impl IioPoller {
     }
   }
 
+  pub fn probe(options: &IioOptions) -> Result<String> {
+    let ctx = Self::open_context(options)?;
+    let discovered = discover_best_device(&ctx, &options.timestamp, options.device.as_deref())?;
+
+    Ok(format!(
+      "id={:?} name={:?} gyro={} timestamp={} temp={}",
+      discovered.dev.id(),
+      discovered.dev.name(),
+      discovered.gyro.is_some(),
+      discovered.timestamp.is_some(),
+      discovered.temp.is_some(),
+    ))
+  }
+
+  pub fn list_devices(options: &IioOptions) -> Result<Vec<DeviceSummary>> {
+    let ctx = Self::open_context(options)?;
+
+    Ok(list_devices(
+      &ctx,
+      &options.timestamp,
+      options.device.as_deref(),
+    ))
+  }
+
+  pub fn device_attrs(options: &IioOptions, device: &str) -> Result<Vec<DeviceAttr>> {
+    let ctx = Self::open_context(options)?;
+    let Some(dev) = ctx.find_device(device) else {
+      return Err(Error::invalid_state(format!(
+        "iio device {device:?} not found"
+      )));
+    };
+
+    Ok(enumerate_device_attrs(&ctx, &dev))
+  }
+
+  fn open_context(options: &IioOptions) -> Result<iio::Context> {
+    let ctx = match options.uri.as_deref() {
+      Some(uri) => iio::Context::from_uri(uri).map_err(|err| {
+        Error::invalid_state_with(
+          format!("failed to open iio context {uri:?}: {}", uri_hint(uri)),
+          err,
+        )
+      })?,
+      None => iio::Context::with_backend(iio::Backend::Default)?,
+    };
+
+    if let Some(timeout) = options.sample_timeout {
+      ctx.set_timeout(timeout)?;
+    }
+
+    Ok(ctx)
+  }
+
   fn open_best_in_context(
     ctx: &iio::Context,
-    rate_hz: u32,
+    options: &IioOptions,
     trigger_guard: Option<TriggerGuard>,
   ) -> Result<Self> {
-    let discovered = discover_best_device(ctx)?;
-    let poller = BufferPoller::new(ctx, &discovered, rate_hz)?;
+    let discovered = discover_best_device(ctx, &options.timestamp, options.device.as_deref())?;
+    let mut poller = BufferPoller::new(ctx, &discovered, options)?;
+    let rate_hz = options.rate_hz_override.unwrap_or(options.rate_hz);
+    let mut last_timestamp_ns = None;
+
+    poller.warm_up(options.warmup_reads, rate_hz, &mut last_timestamp_ns)?;
 
     Ok(Self {
       poller,
       rate_hz,
-      last_timestamp_ns: None,
+      rate_locked: options.rate_hz_override.is_some(),
+      gyro_unit: options.gyro_unit,
+      accel_unit: options.accel_unit,
+      unit_magnitudes: Vec::new(),
+      gravity_checked: false,
+      max_accel_mps2: options.max_accel_mps2,
+      clamp_accel: options.clamp_accel,
+      clamped_samples: options.clamped_samples.clone().unwrap_or_default(),
+      saturated_samples: options.saturated_samples.clone().unwrap_or_default(),
+      last_timestamp_ns,
       trigger_guard,
     })
   }
 
+  pub fn device_info(&self) -> DeviceInfo {
+    self.poller.device_info()
+  }
+
   pub fn read_sample(&mut self) -> Result<ImuSample> {
+    match self.try_read_sample()? {
+      Some(sample) => Ok(sample),
+      None => Err(self.poller.timeout_error(self.rate_hz)),
+    }
+  }
+
+  // A refill timeout is reported as Ok(None) so callers whose trigger may
+  // legitimately stay idle can poll again without going through the error path.
+  pub fn try_read_sample(&mut self) -> Result<Option<ImuSample>> {
     let _ = self.trigger_guard.as_ref();
 
-    self
+    let mut sample = match self
       .poller
       .read_sample(self.rate_hz, &mut self.last_timestamp_ns)
+    {
+      Ok(sample) => sample,
+      Err(Error::IioTimeout(_)) => return Ok(None),
+      Err(err) => return Err(err),
+    };
+
+    if sample.accel_saturated {
+      self.saturated_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let accel_unit = match self.accel_unit {
+      AccelUnit::Auto => self.detect_accel_unit(sample.accel_mps2),
+      unit => unit,
+    };
+
+    if accel_unit == AccelUnit::G {
+      for v in &mut sample.accel_mps2 {
+        *v *= STANDARD_GRAVITY;
+      }
+    }
+
+    self.check_accel_range(&mut sample.accel_mps2)?;
+
+    if !self.gravity_checked && self.accel_unit != AccelUnit::Auto {
+      self.gravity_checked = true;
+      check_stationary_gravity(sample.accel_mps2);
+    }
+
+    if self.gyro_unit == GyroUnit::Deg
+      && let Some(gyro) = sample.gyro.as_mut()
+    {
+      for v in gyro {
+        *v = v.to_radians();
+      }
+    }
+
+    Ok(Some(sample))
+  }
+
+  // Until enough samples are in, each one is converted by the median so far;
+  // a single knock or zero reading cannot pick the unit for the whole run.
+  fn detect_accel_unit(&mut self, accel: [f64; 3]) -> AccelUnit {
+    let magnitude = (accel[0].powi(2) + accel[1].powi(2) + accel[2].powi(2)).sqrt();
+
+    if magnitude > 0.0 && magnitude.is_finite() {
+      self.unit_magnitudes.push(magnitude);
+    }
+
+    let Some(median) = median(&mut self.unit_magnitudes) else {
+      return AccelUnit::Auto;
+    };
+    let unit = accel_unit_for_magnitude(median);
+
+    if self.unit_magnitudes.len() >= ACCEL_UNIT_DETECT_SAMPLES {
+      if unit == AccelUnit::G {
+        eprintln!(
+          "warning: stationary accel magnitude {median:.3} looks like g rather than m/s^2, converting; pass --accel-unit to override"
+        );
+      }
+
+      self.accel_unit = unit;
+      self.unit_magnitudes = Vec::new();
+    }
+
+    unit
+  }
+
+  fn check_accel_range(&mut self, accel: &mut [f64; 3]) -> Result<()> {
+    let limit = self.max_accel_mps2;
+
+    if accel.iter().all(|v| v.abs() <= limit) {
+      return Ok(());
+    }
+
+    if !self.clamp_accel {
+      return Err(Error::invalid_state("accel sample out of range"));
+    }
+
+    let clamped = self.clamped_samples.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if clamped % 100 == 1 {
+      eprintln!(
+        "warning: clamped accel sample {accel:?} to +-{limit} m/s^2 ({clamped} clamped so far)"
+      );
+    }
+
+    for v in accel {
+      *v = v.clamp(-limit, limit);
+    }
+
+    Ok(())
+  }
+}
+
+pub fn parse_uri(uri: &str) -> Result<String> {
+  let uri = uri.trim();
+  let Some((scheme, rest)) = uri.split_once(':') else {
+    return Err(Error::invalid_state(format!(
+      "iio uri {uri:?} has no backend prefix; expected local:, ip:, usb:, serial: or xml:"
+    )));
+  };
+
+  if !matches!(scheme, "local" | "ip" | "usb" | "serial" | "xml") {
+    return Err(Error::invalid_state(format!(
+      "unknown iio backend {scheme:?}; expected local, ip, usb, serial or xml"
+    )));
+  }
+
+  if matches!(scheme, "usb" | "serial" | "xml") && rest.is_empty() {
+    return Err(Error::invalid_state(format!(
+      "iio uri {uri:?} is missing the {scheme} device"
+    )));
+  }
+
+  Ok(uri.to_string())
+}
+
+fn uri_hint(uri: &str) -> &'static str {
+  match uri.split_once(':').map(|(scheme, _)| scheme) {
+    Some("ip") => "is iiod running and reachable on that host",
+    Some("usb") => "is the device attached and accessible, check lsusb and udev permissions",
+    Some("serial") => "is the port present and not held open by another process",
+    Some("xml") => "does the xml file exist and describe a valid context",
+    _ => "is libiio built with this backend",
+  }
+}
+
+fn check_stationary_gravity(accel: [f64; 3]) {
+  let magnitude = (accel[0].powi(2) + accel[1].powi(2) + accel[2].powi(2)).sqrt();
+
+  if !(0.8 * STANDARD_GRAVITY..=1.2 * STANDARD_GRAVITY).contains(&magnitude) {
+    eprintln!(
+      "warning: startup accel magnitude {magnitude:.3} m/s^2 ({accel:?}) is far from g; check the channel scale or --accel-unit if the device was at rest"
+    );
+  }
+}
+
+fn accel_unit_for_magnitude(magnitude: f64) -> AccelUnit {
+  if magnitude >= ACCEL_UNIT_SPLIT {
+    AccelUnit::Mps2
+  } else {
+    AccelUnit::G
+  }
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+  if values.is_empty() {
+    return None;
+  }
+
+  let mid = values.len() / 2;
+  let (_, median, _) = values.select_nth_unstable_by(mid, f64::total_cmp);
+
+  Some(*median)
+}
+
+impl SensorPoller for IioPoller {
+  fn read_sample(&mut self) -> Result<ImuSample> {
+    IioPoller::read_sample(self)
+  }
+
+  fn describe(&self) -> String {
+    self.poller.describe()
+  }
+
+  fn check_rate(&self, rate_hz: u32) -> Result<()> {
+    if self.rate_locked {
+      return Err(Error::invalid_state(format!(
+        "rate is fixed at {}hz by --rate-hz-override",
+        self.rate_hz
+      )));
+    }
+
+    match self.poller.unsupported_rate(rate_hz) {
+      Some(available) => Err(Error::invalid_state(format!(
+        "rate {rate_hz}hz is not in sampling_frequency_available ({available})"
+      ))),
+      None => Ok(()),
+    }
+  }
+
+  fn device_id(&self) -> Option<String> {
+    self.poller.device_id()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ACCEL_UNIT_SPLIT, AccelUnit, accel_unit_for_magnitude, median};
+
+  #[test]
+  fn median_ignores_a_single_outlier() {
+    let mut values = [9.8, 9.7, 0.9, 9.9, 9.8];
+
+    assert_eq!(median(&mut values), Some(9.8));
+    assert_eq!(median(&mut []), None);
+  }
+
+  #[test]
+  fn magnitude_split_between_g_and_mps2() {
+    assert_eq!(accel_unit_for_magnitude(1.0), AccelUnit::G);
+    assert_eq!(accel_unit_for_magnitude(9.81), AccelUnit::Mps2);
+    assert_eq!(accel_unit_for_magnitude(ACCEL_UNIT_SPLIT), AccelUnit::Mps2);
   }
 }
@@ -0,0 +1,141 @@
+use super::IioOptions;
+use super::discovery::discover_best_device;
+use super::trigger::{hrtimer_configfs_available, is_device_access_error};
+use super::types::PreflightIssue;
+use crate::error::Error;
+use industrial_io as iio;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+const SYSFS_IIO_DEVICES: &str = "/sys/bus/iio/devices";
+
+// Only reads state: nothing here escalates, creates triggers or writes sysfs,
+// so problems the open path can fix itself are reported as warnings.
+pub fn preflight_issues(options: &IioOptions) -> Vec<PreflightIssue> {
+  let remote = options
+    .uri
+    .as_deref()
+    .is_some_and(|uri| !uri.starts_with("local:"));
+  let ctx = match options.uri.as_deref() {
+    Some(uri) => iio::Context::from_uri(uri),
+    None => iio::Context::with_backend(iio::Backend::Default),
+  };
+  let ctx = match ctx {
+    Ok(ctx) => ctx,
+    Err(err) => {
+      let hint = if is_device_access_error(&err) {
+        "run as root or add a udev rule granting access to /sys/bus/iio"
+      } else if remote {
+        "check that iiod is running on the remote host and the uri is correct"
+      } else {
+        "load the industrialio module and check that /sys/bus/iio exists"
+      };
+
+      return vec![PreflightIssue {
+        problem: format!("cannot open iio context: {err}"),
+        hint,
+        // Access errors are retried with escalated privileges on open.
+        fatal: !is_device_access_error(&err),
+      }];
+    }
+  };
+  let mut issues = Vec::new();
+
+  if !ctx.devices().any(|dev| dev.is_trigger()) {
+    if remote {
+      issues.push(PreflightIssue {
+        problem: "no iio trigger on the remote context".to_string(),
+        hint: "create an hrtimer or sysfs trigger on the remote host before starting",
+        fatal: true,
+      });
+    } else if !hrtimer_configfs_available() {
+      issues.push(PreflightIssue {
+        problem: "no iio trigger and the hrtimer configfs is unavailable".to_string(),
+        hint: "load industrialio-sw-trigger and iio-trig-hrtimer, then mount configfs on /sys/kernel/config",
+        fatal: true,
+      });
+    }
+  }
+
+  let discovered = match discover_best_device(&ctx, &options.timestamp, options.device.as_deref()) {
+    Ok(discovered) => discovered,
+    Err(err) => {
+      issues.push(PreflightIssue {
+        problem: err.to_string(),
+        hint: match err {
+          Error::SensorNotFound(_) => "load the imu driver module and check dmesg for probe errors",
+          _ => {
+            "list devices with `iio_info` and pass an id or name that has buffered accel channels"
+          }
+        },
+        fatal: true,
+      });
+
+      return issues;
+    }
+  };
+
+  if !remote
+    && let Some(id) = discovered.dev.id()
+    && !buffer_writable(&id)
+  {
+    issues.push(PreflightIssue {
+      problem: format!("{SYSFS_IIO_DEVICES}/{id}/buffer/enable is not writable"),
+      hint: "yured will try to escalate privileges when opening; run as root or add a udev rule to avoid it",
+      fatal: false,
+    });
+  }
+
+  if options.rate_hz_override.is_none()
+    && let Some(available) = unsupported_rate(&discovered.dev, options.rate_hz)
+  {
+    issues.push(PreflightIssue {
+      problem: format!(
+        "rate {}hz is not in sampling_frequency_available ({available})",
+        options.rate_hz
+      ),
+      hint: "pick one of the listed rates with --rate; the driver may round to the nearest one",
+      fatal: false,
+    });
+  }
+
+  issues
+}
+
+fn buffer_writable(id: &str) -> bool {
+  let path = Path::new(SYSFS_IIO_DEVICES).join(id).join("buffer/enable");
+
+  OpenOptions::new().write(true).open(path).is_ok()
+}
+
+// Drivers report either a discrete list or a `[min step max]` range; only the
+// discrete form is checked.
+pub(super) fn unsupported_rate(dev: &iio::Device, rate_hz: u32) -> Option<String> {
+  let available = dev
+    .attr_read_str("sampling_frequency_available")
+    .ok()?
+    .trim()
+    .to_string();
+
+  if available.is_empty() || available.starts_with('[') {
+    return None;
+  }
+
+  let supported = available
+    .split_whitespace()
+    .filter_map(|rate| rate.parse::<f64>().ok())
+    .any(|rate| (rate - f64::from(rate_hz)).abs() < 0.5);
+
+  (!supported).then_some(available)
+}
+
+pub fn preflight_report(issues: &[PreflightIssue]) -> String {
+  let mut out = String::new();
+
+  for issue in issues {
+    let _ = write!(out, "\n  - {}\n    hint: {}", issue.problem, issue.hint);
+  }
+
+  out
+}
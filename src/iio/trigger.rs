@@ -18,12 +18,12 @@ impl Drop for TriggerGuard {
   }
 }
 
+pub fn hrtimer_configfs_available() -> bool {
+  Path::new(HRTIMER_TRIGGER_BASE).is_dir()
+}
+
 pub fn ensure_trigger_device() -> Result<Option<TriggerGuard>> {
-  karen::escalate_if_needed().map_err(|err| {
-    Error::invalid_state(format!(
-      "failed to escalate privileges for trigger creation: {err}"
-    ))
-  })?;
+  escalate("trigger creation")?;
 
   create_hrtimer_trigger(DEFAULT_HRTIMER_TRIGGER)
 }
@@ -47,9 +47,10 @@ fn create_hrtimer_trigger(name: &str) -> Result<Option<TriggerGuard>> {
     }
 
     Err(err) => {
-      return Err(Error::invalid_state(format!(
-        "failed to access iio hrtimer configfs: {err}"
-      )));
+      return Err(Error::invalid_state_with(
+        "failed to access iio hrtimer configfs",
+        err,
+      ));
     }
   }
 
@@ -58,13 +59,24 @@ fn create_hrtimer_trigger(name: &str) -> Result<Option<TriggerGuard>> {
   match fs::create_dir(&path) {
     Ok(()) => Ok(Some(TriggerGuard { path })),
     Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
-    Err(err) => Err(Error::invalid_state(format!(
-      "failed to create iio hrtimer trigger at {}: {err}",
-      path.display()
-    ))),
+    Err(err) => Err(Error::invalid_state_with(
+      format!("failed to create iio hrtimer trigger at {}", path.display()),
+      err,
+    )),
   }
 }
 
+pub fn escalate(purpose: &str) -> Result<()> {
+  karen::escalate_if_needed()
+    .map(|_running_as| ())
+    .map_err(|err| {
+      Error::invalid_state_with(
+        format!("failed to escalate privileges for {purpose}"),
+        err.to_string(),
+      )
+    })
+}
+
 pub fn is_device_busy_error(err: &iio::Error) -> bool {
   match err {
     iio::Error::Nix(errno) => *errno == Errno::EBUSY,
@@ -102,11 +114,7 @@ pub fn disable_iio_buffer(dev: &iio::Device) -> Result<()> {
   if let Err(ref err) = result
     && is_device_access_error(err)
   {
-    karen::escalate_if_needed().map_err(|err| {
-      Error::invalid_state(format!(
-        "failed to escalate privileges for iio buffer control: {err}"
-      ))
-    })?;
+    escalate("iio buffer control")?;
     result = dev.attr_write_bool("buffer/enable", false);
   }
 
@@ -118,6 +126,15 @@ pub fn configure_sampling_frequency(
   trigger: Option<&iio::Device>,
   chans: &[&ChannelConfig],
   rate_hz: u32,
+) -> Result<()> {
+  configure_trigger_frequency(dev, trigger, rate_hz)?;
+  configure_device_frequency(dev, chans, rate_hz)
+}
+
+pub fn configure_trigger_frequency(
+  dev: &iio::Device,
+  trigger: Option<&iio::Device>,
+  rate_hz: u32,
 ) -> Result<()> {
   let rate = i64::from(rate_hz);
 
@@ -129,11 +146,7 @@ pub fn configure_sampling_frequency(
     if let Err(ref err) = result
       && is_device_access_error(err)
     {
-      karen::escalate_if_needed().map_err(|err| {
-        Error::invalid_state(format!(
-          "failed to escalate privileges for trigger access: {err}"
-        ))
-      })?;
+      escalate("trigger access")?;
       result = trigger.attr_write_int("sampling_frequency", rate);
     }
 
@@ -150,16 +163,22 @@ pub fn configure_sampling_frequency(
     }
   }
 
+  Ok(())
+}
+
+fn configure_device_frequency(
+  dev: &iio::Device,
+  chans: &[&ChannelConfig],
+  rate_hz: u32,
+) -> Result<()> {
+  let rate = i64::from(rate_hz);
+
   if dev.has_attr("sampling_frequency") {
     let mut result = dev.attr_write_int("sampling_frequency", rate);
     if let Err(ref err) = result
       && is_device_access_error(err)
     {
-      karen::escalate_if_needed().map_err(|err| {
-        Error::invalid_state(format!(
-          "failed to escalate privileges for device access: {err}"
-        ))
-      })?;
+      escalate("device access")?;
       result = dev.attr_write_int("sampling_frequency", rate);
     }
 
@@ -185,11 +204,7 @@ pub fn configure_sampling_frequency(
     if let Err(ref err) = result
       && is_device_access_error(err)
     {
-      karen::escalate_if_needed().map_err(|err| {
-        Error::invalid_state(format!(
-          "failed to escalate privileges for channel access: {err}"
-        ))
-      })?;
+      escalate("channel access")?;
       result = chan.chan.attr_write_int("sampling_frequency", rate);
     }
 
@@ -208,7 +223,11 @@ pub fn configure_sampling_frequency(
   Ok(())
 }
 
-pub fn set_trigger(dev: &iio::Device, trigger: Option<&iio::Device>) -> Result<Option<String>> {
+pub fn set_trigger(
+  dev: &iio::Device,
+  trigger: Option<&iio::Device>,
+  verbose_iio: bool,
+) -> Result<Option<String>> {
   let Some(trigger) = trigger else {
     return Ok(None);
   };
@@ -219,11 +238,7 @@ pub fn set_trigger(dev: &iio::Device, trigger: Option<&iio::Device>) -> Result<O
   if let Err(ref err) = result
     && is_device_access_error(err)
   {
-    karen::escalate_if_needed().map_err(|err| {
-      Error::invalid_state(format!(
-        "failed to escalate privileges for trigger selection: {err}"
-      ))
-    })?;
+    escalate("trigger selection")?;
     result = dev.set_trigger(trigger);
   }
 
@@ -234,15 +249,56 @@ pub fn set_trigger(dev: &iio::Device, trigger: Option<&iio::Device>) -> Result<O
     result = dev.set_trigger(trigger);
   }
 
+  if result.is_err() && verbose_iio {
+    log_trigger_debug_info(trigger);
+  }
+
   match result {
     Ok(()) => Ok(trigger_name),
     Err(err) if is_device_busy_error(&err) => Err(err.into()),
-    Err(err) => Err(Error::invalid_state(format!(
-      "failed to set trigger {trigger_name:?}: {err}"
-    ))),
+    Err(err) => Err(Error::invalid_state_with(
+      format!("failed to set trigger {trigger_name:?}"),
+      err,
+    )),
+  }
+}
+
+pub fn log_trigger_debug_info(trigger: &iio::Device) {
+  eprintln!(
+    "iio trigger debug: id={:?} name={:?}",
+    trigger.id(),
+    trigger.name()
+  );
+
+  for attr in trigger.attributes() {
+    match trigger.attr_read_str(&attr) {
+      Ok(value) => eprintln!("iio trigger debug: {attr}={:?}", value.trim()),
+      Err(err) => eprintln!("iio trigger debug: {attr}=<unreadable: {err}>"),
+    }
   }
 }
 
-pub fn select_trigger(triggers: &[iio::Device]) -> Option<iio::Device> {
-  triggers.first().cloned()
+pub fn select_trigger(
+  triggers: &[iio::Device],
+  pattern: Option<&str>,
+  exact: bool,
+) -> Option<iio::Device> {
+  let Some(pattern) = pattern else {
+    return triggers.first().cloned();
+  };
+  let matched = triggers.iter().find(|trigger| {
+    trigger.name().is_some_and(|name| {
+      if exact {
+        name == pattern
+      } else {
+        name.contains(pattern)
+      }
+    })
+  });
+
+  if matched.is_none() {
+    eprintln!("warning: no iio trigger matches {pattern:?}; falling back to the first trigger");
+  }
+
+  matched.or_else(|| triggers.first()).cloned()
 }
@@ -1,4 +1,6 @@
+use super::channel::SampleType;
 use industrial_io as iio;
+use serde::Serialize;
 
 #[derive(Debug, Clone)]
 pub struct AxisSet<T> {
@@ -13,6 +15,45 @@ pub struct DiscoveredDevice {
   pub accel: AxisSet<iio::Channel>,
   pub gyro: Option<AxisSet<iio::Channel>>,
   pub timestamp: Option<iio::Channel>,
+  pub temp: Option<iio::Channel>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+  pub id: Option<String>,
+  pub name: Option<String>,
+  pub firmware_version: Option<String>,
+  pub fifo_watermark_max: Option<u32>,
+  pub sample_size: Option<usize>,
+  pub accel_channels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceSummary {
+  pub id: Option<String>,
+  pub name: Option<String>,
+  pub has_gyro: bool,
+  pub has_timestamp: bool,
+  pub is_selected: bool,
+  pub accel_channels: Vec<String>,
+  pub sample_types: Vec<Option<SampleType>>,
+  pub scales: Vec<Option<f64>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceAttr {
+  pub name: String,
+  pub value: Option<String>,
+  pub kind: &'static str,
+  pub writable: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PreflightIssue {
+  pub problem: String,
+  pub hint: &'static str,
+  pub fatal: bool,
 }
 
 impl<T> AxisSet<T> {
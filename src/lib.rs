@@ -0,0 +1,12 @@
+//! Fusion and sample pipeline behind the yured daemon, usable without the iio and
+//! websocket layers: push `sensor::ImuSample`s into a `pipeline::Pipeline` and drain
+//! `pipeline::MotionSample`s from its `SampleQueue`.
+#![deny(clippy::all, clippy::pedantic)]
+// The exported API only panics on poisoned mutex locks or a clock set before 1970.
+#![allow(clippy::missing_panics_doc)]
+#![feature(duration_millis_float)]
+pub mod clock;
+pub mod error;
+pub mod fusion;
+pub mod pipeline;
+pub mod sensor;
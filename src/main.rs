@@ -1,24 +1,43 @@
 #![deny(clippy::all, clippy::pedantic)]
-#![feature(duration_millis_float)]
-mod error;
-mod fusion;
 mod iio;
+mod metrics;
+mod platform;
+mod profile;
 mod ws;
 mod yure;
 
-use crate::fusion::Algorithm;
-use crate::yure::generate_user_agent;
-use clap::Parser;
-use error::Result;
+use yured::{clock, error, fusion, pipeline, sensor};
+
+use crate::fusion::{Algorithm, EkfNoise, GravityDirection, GravitySource};
+use crate::yure::{DEFAULT_USER_AGENT_TEMPLATE, generate_user_agent};
+use clap::{Parser, Subcommand, ValueEnum};
+use error::{Error, Result};
 use fusion::FusionEngine;
-use iio::IioPoller;
-use ringbuffer::{AllocRingBuffer, RingBuffer};
-use std::sync::{Arc, Condvar, Mutex, mpsc};
+use iio::{AccelUnit, GyroUnit, IioOptions, IioPoller, JitterHistogram, TimestampSource};
+use metrics::{Metrics, PushgatewaySink};
+use pipeline::{
+  IDLE_DOWNCLOCK_FACTOR, IdleDownclock, JerkDifferentiator, MotionSample, Pipeline, PipelineEvent,
+  PipelineInput, RmsAccumulator, SampleQueue, SampleQueueMode, sample_clock_ms,
+};
+use platform::CpuList;
+use profile::Profiler;
+use sensor::{Backend, SensorPoller, SyntheticImuPoller};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, mpsc};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use ws::WsClient;
-use yure::{StreamBatcher, YureSample, generate_yure_id};
+use std::time::{Duration, Instant};
+use ws::{FailoverWsClient, SendRateLimiter, ServerCommand, WsClient};
+use yure::{
+  Axes, BatchStrategy, CsvWriter, FieldNames, OutputMode, StreamBatcher, YureSample,
+  generate_yure_id, generate_yure_id_seeded,
+};
+
+const SERVER_COMMAND_QUEUE: usize = 8;
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
 
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Clone, Debug, Parser)]
 #[command(name = "yured")]
 pub struct Config {
@@ -29,6 +48,9 @@ pub struct Config {
     value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
   )]
   pub batch: usize,
+  /// size:N, time:MS or hybrid:N:MS; overrides --batch for flushing
+  #[arg(long, value_name = "STRATEGY", value_parser = BatchStrategy::parse)]
+  pub batch_strategy: Option<BatchStrategy>,
   #[arg(
     long,
     short,
@@ -36,163 +58,1486 @@ pub struct Config {
     value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..),
   )]
   pub rate: u32,
+  /// Fixed rate of a locked-down driver; skips writing the driver sampling frequency and
+  /// uses this value instead of --rate for the trigger, fusion, rms window and dt fallback
+  #[arg(
+    long,
+    value_name = "HZ",
+    value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..),
+  )]
+  pub rate_hz_override: Option<u32>,
   #[arg(long, short, value_enum, default_value_t = Algorithm::Madgwick)]
   pub algorithm: Algorithm,
+  /// lowpass ignores --algorithm and estimates gravity from low-passed accel only
+  #[arg(long, value_enum, default_value_t = GravitySource::Fusion)]
+  pub gravity_source: GravitySource,
+  /// Sensor axis that reads +1 g at rest in this mounting, e.g. -z when mounted upside down;
+  /// samples are rotated onto +z for fusion, so tilt and orientation are relative to the mount
+  #[arg(
+    long,
+    value_enum,
+    default_value_t = GravityDirection::PosZ,
+    allow_hyphen_values = true
+  )]
+  pub gravity_direction: GravityDirection,
+  #[arg(long, value_enum, default_value_t = Backend::Iio)]
+  pub backend: Backend,
+  /// Shorthand for --backend synthetic
+  #[arg(long)]
+  pub synthetic: bool,
+  /// Process samples without connecting to the websocket server
+  #[arg(long)]
+  pub dry_run: bool,
+  /// With --output-csv, the per-sample JSON moves to stderr
   #[arg(long, short)]
   pub verbose: bool,
+  /// Write samples to stdout as RFC 4180 CSV rows, flushed whenever a batch completes
+  #[arg(long)]
+  pub output_csv: bool,
+  /// Record pipeline timings for this many seconds, print a JSON report and exit
+  #[arg(long, value_name = "SECS")]
+  pub profile: Option<u64>,
+  /// Print build, fusion library and device details, then exit
+  #[arg(long)]
+  pub version_full: bool,
+  #[command(subcommand)]
+  pub command: Option<Command>,
+  #[arg(long, value_name = "FIELD=NAME,...", value_parser = FieldNames::parse)]
+  pub rename: Option<FieldNames>,
+  #[arg(long, value_name = "AXES", default_value = "xyz", value_parser = Axes::parse)]
+  pub axes: Axes,
+  /// tilt emits pitch/roll/yaw in degrees using the aerospace ZYX convention;
+  /// jerk emits the derivative of linear accel in m/s^3 as x/y/z
+  #[arg(long, value_enum, default_value_t = OutputMode::Linear)]
+  pub output: OutputMode,
+  /// Scale the madgwick/mahony accel correction down as |accel| departs from 1g; for ekf
+  /// it inflates the accel measurement noise instead
+  #[arg(long)]
+  pub adaptive_gain: bool,
+  /// Quaternion process noise per second for --algorithm ekf; larger trusts the gyro less
+  #[arg(long, value_name = "VAR", default_value_t = fusion::EKF_PROCESS_NOISE)]
+  pub ekf_process_noise: f64,
+  /// Variance of the normalized accel direction for --algorithm ekf; larger trusts
+  /// the accel less
+  #[arg(long, value_name = "VAR", default_value_t = fusion::EKF_MEASUREMENT_NOISE)]
+  pub ekf_measurement_noise: f64,
+  /// Integrate madgwick/mahony/ekf/lowpass with the nominal 1/rate period instead of the
+  /// measured dt; steadier on a jittery trigger, but orientation drifts if the real rate
+  /// differs from --rate
+  #[arg(long)]
+  pub fixed_dt: bool,
+  /// Smooth dt with a scalar kalman filter fusing hardware timestamp deltas and the nominal period
+  #[arg(long, conflicts_with = "fixed_dt")]
+  pub dt_kalman: bool,
+  /// Lowpass cutoff applied to --output jerk; unset leaves it unfiltered
+  #[arg(long, value_name = "HZ")]
+  pub jerk_cutoff_hz: Option<f64>,
+  #[arg(long)]
+  pub output_magnitude: bool,
+  #[arg(
+    long,
+    value_name = "NAME",
+    default_value = "m",
+    requires = "output_magnitude"
+  )]
+  pub output_magnitude_field_name: String,
+  #[arg(long, value_name = "N", default_value_t = 5)]
+  pub ws_max_rejections: u32,
+  #[arg(long, value_name = "SECS", default_value_t = 600)]
+  pub ws_circuit_open_secs: u64,
+  #[arg(long, value_name = "URL")]
+  pub ws_failover_url: Option<url::Url>,
+  #[arg(long, value_name = "N", default_value_t = 3)]
+  pub ws_failover_after: u32,
+  #[arg(long, value_name = "SECS", default_value_t = 60)]
+  pub ws_primary_recover_after: u64,
+  #[arg(long, value_name = "BYTES", default_value_t = ws::DEFAULT_MAX_MESSAGE_SIZE)]
+  pub ws_max_message_size: usize,
+  /// Split batches into continuation frames of at most this size, 0 disables; the server
+  /// must reassemble fragmented messages before parsing
+  #[arg(long, value_name = "KB", default_value_t = 0)]
+  pub ws_max_frame_kb: usize,
+  /// Maximum batches sent per second, 0 is unlimited
+  #[arg(long, value_name = "HZ", default_value_t = 0.0)]
+  pub ws_send_rate_hz: f64,
+  /// Encoded batches queued for the websocket thread, default 4x --batch; deeper rides out
+  /// longer stalls at the cost of memory and latency, shallower drops batches sooner
+  #[arg(
+    long,
+    value_name = "N",
+    value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+  )]
+  pub ws_channel_depth: Option<usize>,
+  /// Socket read timeout; also bounds how long the websocket loop waits for incoming frames
+  #[arg(long, value_name = "MS", default_value_t = 10)]
+  pub ws_read_timeout_ms: u64,
+  /// Socket write timeout; a stalled peer makes sends retry instead of blocking the websocket loop
+  #[arg(long, value_name = "MS", default_value_t = 5000)]
+  pub ws_write_timeout_ms: u64,
+  /// Resize size and hybrid batches to the websocket ping round trip times --rate, clamped
+  /// to --min-batch and --max-batch
+  #[arg(long)]
+  pub adaptive_batch: bool,
+  #[arg(
+    long,
+    value_name = "N",
+    default_value_t = 5,
+    requires = "adaptive_batch"
+  )]
+  pub min_batch: usize,
+  #[arg(
+    long,
+    value_name = "N",
+    default_value_t = 60,
+    requires = "adaptive_batch"
+  )]
+  pub max_batch: usize,
+  /// How often --adaptive-batch pings the server to measure the round trip
+  #[arg(
+    long,
+    value_name = "MS",
+    default_value_t = 1000,
+    requires = "adaptive_batch"
+  )]
+  pub ws_ping_interval_ms: u64,
+  #[arg(long, value_name = "URL")]
+  pub pushgateway_url: Option<url::Url>,
+  #[arg(long, value_name = "SECS", default_value_t = 30)]
+  pub pushgateway_interval_secs: u64,
+  #[arg(long)]
+  pub batch_crc: bool,
+  /// Only applies to envelope version 1 and later
+  #[arg(long)]
+  pub include_batch_stats: bool,
+  /// Declare sample units in each envelope; only applies to envelope version 1 and later
+  #[arg(long)]
+  pub include_units: bool,
+  #[arg(long)]
+  pub kernel_version_check: bool,
+  /// Skip the startup probe for missing sensors, triggers, permissions and unsupported rates
+  #[arg(long)]
+  pub no_preflight: bool,
+  #[arg(long)]
+  pub emit_raw: bool,
+  /// Add `raw_x`/`raw_y`/`raw_z` with the pre-fusion accel in m/s^2, gravity included
+  #[arg(long)]
+  pub include_raw: bool,
+  /// Add `gx`/`gy`/`gz` with the gyro in rad/s, null without a gyro; the only bias correction
+  /// applied is the --gyro-temp-slope compensation, there is no at-rest bias calibration
+  #[arg(long)]
+  pub include_gyro: bool,
+  #[arg(long)]
+  pub include_quality: bool,
+  /// Derive the yureId from a fixed seed so it is reproducible across runs
+  #[arg(long, value_name = "SEED")]
+  pub seed: Option<u64>,
+  #[arg(
+    long,
+    value_name = "N",
+    default_value_t = 0,
+    value_parser = clap::value_parser!(u8).range(0..=99),
+  )]
+  pub rt_priority: u8,
+  #[arg(long, value_name = "CPULIST", value_parser = CpuList::parse)]
+  pub cpu_affinity: Option<CpuList>,
+  #[arg(long, value_name = "MS")]
+  pub sample_timeout_ms: Option<u64>,
+  /// Wait this long after opening the sensor before the first read, for slow driver init
+  #[arg(long, value_name = "MS", default_value_t = 0)]
+  pub startup_delay_ms: u64,
+  /// Stamp samples with milliseconds since boot, counting through suspend, instead of unix
+  /// wall time; only changes `t`, fusion dt still comes from the iio timestamps
+  #[arg(long)]
+  pub use_monotonic: bool,
+  /// Read and drop this many samples once after startup before feeding the pipeline;
+  /// unlike --warmup-reads this does not repeat when the sensor is reopened
+  #[arg(long, value_name = "N", default_value_t = 0)]
+  pub startup_discard_samples: u64,
+  /// Reads dropped right after each iio buffer is created, to flush stale driver FIFO data;
+  /// runs again on every reopen (rate changes, device failover), timeouts and EBUSY retried
+  #[arg(long, value_name = "N", default_value_t = 0)]
+  pub warmup_reads: u32,
+  /// Exit after this many consecutive sensor read errors so a supervisor can restart;
+  /// 0 keeps the default of exiting on the first error
+  #[arg(long, value_name = "N", default_value_t = 0)]
+  pub max_consecutive_errors: u32,
+  /// 0 sends the legacy bare array with yureId/userAgent on every sample
+  #[arg(
+    long,
+    value_name = "VERSION",
+    default_value_t = 1,
+    value_parser = clap::value_parser!(u8).range(0..=1),
+  )]
+  pub envelope_version: u8,
+  /// Only for broken drivers whose scaled gyro output is still in deg/s
+  #[arg(long, value_enum, default_value_t = GyroUnit::Rad)]
+  pub gyro_unit: GyroUnit,
+  /// auto assumes the device is stationary at startup and picks g when |accel| is near 1
+  #[arg(long, value_enum, default_value_t = AccelUnit::Auto)]
+  pub accel_unit: AccelUnit,
+  #[arg(long, value_name = "MPS2", default_value_t = 160.0)]
+  pub max_accel_mps2: f64,
+  /// Clamp accel samples beyond --max-accel-mps2 instead of failing the read
+  #[arg(long)]
+  pub clamp_accel: bool,
+  /// Drop accel samples with NaN or Inf components and read again instead of failing
+  #[arg(long)]
+  pub reject_nonfinite: bool,
+  #[arg(long, value_name = "ID", conflicts_with = "no_timestamp")]
+  pub timestamp_channel: Option<String>,
+  #[arg(long)]
+  pub no_timestamp: bool,
+  #[arg(long, value_name = "MS")]
+  pub heartbeat_ms: Option<u64>,
+  /// Process only every 10th sample after sustained stillness until motion resumes
+  #[arg(long)]
+  pub idle_downclock: bool,
+  #[arg(
+    long,
+    value_name = "MPS2",
+    default_value_t = 0.05,
+    requires = "idle_downclock"
+  )]
+  pub idle_threshold_mps2: f64,
+  #[arg(
+    long,
+    value_name = "SECS",
+    default_value_t = 10.0,
+    requires = "idle_downclock"
+  )]
+  pub idle_after_secs: f64,
+  /// Track a sliding-window rms of linear accel and attach it as "rms" every --rms-interval-ms
+  #[arg(long, value_name = "SECS")]
+  pub rms_window_secs: Option<f64>,
+  #[arg(
+    long,
+    value_name = "MS",
+    default_value_t = 1000,
+    requires = "rms_window_secs"
+  )]
+  pub rms_interval_ms: u64,
+  #[arg(long, value_name = "SCALE")]
+  pub accel_implicit_scale: Option<f64>,
+  /// Assign x/y/z to the accel and gyro channels in scan index order instead of by label,
+  /// for drivers that mislabel their axes
+  #[arg(long)]
+  pub sort_axes_by_scan_index: bool,
+  /// Substring of the trigger name to use, e.g. `hrtimer_trig0` or `sysfstrig0`
+  #[arg(long, value_name = "PATTERN")]
+  pub iio_trigger_name: Option<String>,
+  #[arg(long, requires = "iio_trigger_name")]
+  pub iio_trigger_name_exact: bool,
+  /// Dump every attribute of the selected trigger to stderr when it cannot be set
+  #[arg(long)]
+  pub verbose_iio: bool,
+  #[arg(long)]
+  pub report_device_info: bool,
+  /// Log sysfs trigger interval statistics and export them as a histogram metric
+  #[arg(long)]
+  pub trigger_jitter_report: bool,
+  #[arg(
+    long,
+    value_name = "FIRES",
+    default_value_t = 1000,
+    requires = "trigger_jitter_report"
+  )]
+  pub trigger_jitter_samples: u64,
+  /// Placeholders: {app}, {version}, {algo}, {rate}, {os}, {release}, {arch}
+  #[arg(long, value_name = "TEMPLATE", default_value = DEFAULT_USER_AGENT_TEMPLATE)]
+  pub user_agent_template: String,
+  /// libiio context uri, e.g. `ip:192.168.1.10`, `usb:1.2.5` or `serial:/dev/ttyUSB0,115200`
+  #[arg(long, value_name = "URI", value_parser = iio::parse_uri)]
+  pub iio_uri: Option<String>,
+  /// Stream several iio devices (id or name, repeatable), each with its own fusion, tagging
+  /// samples with "sensor"; samples share batches in queue order, so --batch counts all sensors
+  #[arg(long = "sensor", value_name = "ID")]
+  pub sensors: Vec<String>,
+  /// timestamp hands samples to the sender ordered by time, for interleaving several sensors
+  #[arg(long, value_enum, default_value_t = SampleQueueMode::Fifo)]
+  pub queue_mode: SampleQueueMode,
+  /// With --queue-mode timestamp, hold samples until one this much newer arrives so late
+  /// pushes from another sensor thread still sort in; adds this much latency
+  #[arg(long, value_name = "MS", default_value_t = 20)]
+  pub reorder_latency_ms: u64,
+  /// Log the fusion quaternion, gravity estimate, gravity sign and convergence score to
+  /// stderr at this interval, 0 disables
+  #[arg(long, value_name = "MS", default_value_t = 0)]
+  pub print_fusion_state_interval_ms: u64,
+  /// Log the sender queue's drop rate and occupancy to stderr at this interval, 0 disables
+  #[arg(long, value_name = "MS", default_value_t = 0)]
+  pub print_queue_stats_interval_ms: u64,
+  /// Gyro bias drift per degree C, only applied when the device has a temp scan channel
+  #[arg(long, value_name = "DEG_PER_C", allow_negative_numbers = true)]
+  pub gyro_temp_slope: Option<f64>,
+  #[arg(
+    long,
+    value_name = "C",
+    default_value_t = 25.0,
+    allow_negative_numbers = true
+  )]
+  pub gyro_temp_reference: f64,
 }
 
-#[derive(Clone, Copy, Debug)]
-struct MotionSample {
-  accel_linear: [f64; 3],
-  t_ms: f64,
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+  /// List a device's attributes with their values and sysfs write permission (local contexts only)
+  DeviceAttrs {
+    #[arg(long, value_name = "NAME")]
+    device: String,
+    #[arg(long)]
+    json: bool,
+  },
+  /// List iio devices with accel channels and mark the one that would be streamed
+  ListDevices {
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    output_format: ListFormat,
+  },
 }
 
-struct SampleQueue {
-  queue: Mutex<AllocRingBuffer<MotionSample>>,
-  not_empty: Condvar,
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ListFormat {
+  #[default]
+  Table,
+  Json,
 }
 
 fn main() -> Result<()> {
   let config = Config::parse();
-  let rate_hz = config.rate;
-  let mut poller = IioPoller::open_best(rate_hz).unwrap();
-  let yure_id = generate_yure_id();
-  let queue = Arc::new(SampleQueue::new(config.batch));
-  let (tx, rx) = mpsc::sync_channel::<String>(config.batch);
+
+  if config.version_full {
+    print_version_full(&config);
+
+    return Ok(());
+  }
+
+  if let Some(Command::DeviceAttrs { device, json }) = config.command.as_ref() {
+    return print_device_attrs(&config, device, *json);
+  }
+
+  if let Some(Command::ListDevices { output_format }) = config.command.as_ref() {
+    return print_device_list(&config, *output_format);
+  }
+
+  config.validate()?;
+
+  if config.kernel_version_check {
+    platform::check_kernel_version();
+  }
+
+  preflight(&config)?;
+  platform::install_shutdown_handler()?;
+
+  let queue = build_queue(&config);
+  let metrics = Arc::new(build_metrics(&config, &queue));
+  let poller = if config.sensors.is_empty() {
+    Some(open_poller(&config, None, &metrics)?)
+  } else {
+    None
+  };
+  let yure_id = config
+    .seed
+    .map_or_else(generate_yure_id, generate_yure_id_seeded);
+  let names = field_names(&config)?;
+  let (tx, rx) = mpsc::sync_channel::<String>(config.ws_channel_depth());
   let sender_config = config.clone();
   let sender_queue = Arc::clone(&queue);
-  let sender_user_agent = generate_user_agent(config.algorithm, config.rate);
+  let sender_user_agent = generate_user_agent(
+    &config.user_agent_template,
+    config.algorithm,
+    config.effective_rate(),
+  )?;
   let sender_yure_id = yure_id.clone();
   let ws_url = "wss://unstable.kusaremkn.com/yure/".try_into().unwrap();
+  let sender_metrics = Arc::clone(&metrics);
+  let (command_tx, command_rx) = mpsc::sync_channel::<ServerCommand>(SERVER_COMMAND_QUEUE);
 
   thread::spawn(move || {
     sender_loop(
       &sender_config,
+      &names,
       &sender_yure_id,
       &sender_queue,
       &sender_user_agent,
       &tx,
+      &sender_metrics,
     );
   });
 
-  thread::spawn(move || {
-    ws_loop(ws_url, &rx);
-  });
+  if config.dry_run {
+    drop(rx);
+    drop(command_tx);
+  } else {
+    let ws_config = config.clone();
+    let ws_metrics = Arc::clone(&metrics);
+
+    thread::spawn(move || {
+      ws_loop(&ws_config, ws_url, &rx, &command_tx, &ws_metrics);
+    });
+  }
+
+  if let Some(url) = config.pushgateway_url.clone() {
+    spawn_pushgateway(
+      PushgatewaySink::new(url, &yure_id)?,
+      Arc::clone(&metrics),
+      Duration::from_secs(config.pushgateway_interval_secs.max(1)),
+    );
+  }
+
+  spawn_queue_stats_printer(&config, &queue);
+
+  if config.rt_priority > 0 {
+    platform::set_thread_realtime(config.rt_priority)?;
+  }
+
+  if let Some(cpus) = config.cpu_affinity.as_ref() {
+    platform::set_thread_affinity(cpus)?;
+  }
+
+  eprintln!("effective configuration: {config}");
+
+  let Some(poller) = poller else {
+    eprintln!("yureId: {yure_id}");
+
+    return run_sensors(&config, &queue, &metrics, command_rx);
+  };
+  let fusion = build_fusion(&config);
+
+  eprintln!("sensor: {}", poller.describe());
+  match fusion.as_ref() {
+    Some(fusion) => eprintln!("fusion: {}", fusion.describe()),
+    None => eprintln!("fusion: disabled, streaming scaled accel/gyro"),
+  }
 
-  let mut fusion = FusionEngine::new(config.algorithm, rate_hz);
+  let input = build_pipeline(&config, fusion, queue).input();
+
+  spawn_fusion_state_printer(&config, &input, "");
 
   eprintln!("yureId: {yure_id}");
 
+  run_loop(
+    poller,
+    config.clone(),
+    &input,
+    &metrics,
+    config
+      .profile
+      .map(|secs| Profiler::new(Duration::from_secs(secs))),
+    &command_rx,
+  )
+}
+
+fn field_names(config: &Config) -> Result<FieldNames> {
+  let names = config.rename.clone().unwrap_or_default();
+
+  if config.output_magnitude {
+    names.with_magnitude(&config.output_magnitude_field_name)
+  } else {
+    Ok(names)
+  }
+}
+
+fn build_pipeline(
+  config: &Config,
+  fusion: Option<FusionEngine>,
+  queue: Arc<SampleQueue>,
+) -> Pipeline {
+  let pipeline = Pipeline::new(fusion, queue)
+    .with_monotonic(config.use_monotonic)
+    .with_event_handler(log_pipeline_event);
+  let pipeline = if config.output == OutputMode::Jerk {
+    pipeline.with_jerk(JerkDifferentiator::new(config.jerk_cutoff_hz))
+  } else {
+    pipeline
+  };
+  let pipeline = match config.rms_window_secs {
+    Some(secs) => pipeline.with_rms(RmsAccumulator::new(rms_window_samples(
+      secs,
+      config.effective_rate(),
+    ))),
+    None => pipeline,
+  };
+
+  if config.idle_downclock {
+    pipeline.with_idle_downclock(IdleDownclock::new(
+      config.idle_threshold_mps2,
+      config.idle_after_secs,
+    ))
+  } else {
+    pipeline
+  }
+}
+
+fn log_pipeline_event(event: PipelineEvent) {
+  match event {
+    PipelineEvent::IdleEntered { still_secs } => eprintln!(
+      "idle downclock: no motion for {still_secs:.0}s, processing 1/{IDLE_DOWNCLOCK_FACTOR} samples"
+    ),
+    PipelineEvent::IdleExited => eprintln!("idle downclock: motion detected, resuming full rate"),
+    PipelineEvent::AccelSaturated { accel_mps2, count } if count % 100 == 1 => eprintln!(
+      "warning: accel saturated at {accel_mps2:?}, integrating gyro only ({count} times so far)"
+    ),
+    PipelineEvent::AccelSaturated { .. } => {}
+  }
+}
+
+fn rms_window_samples(secs: f64, rate_hz: u32) -> usize {
+  #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+  let samples = (secs * f64::from(rate_hz)).round().max(1.0) as usize;
+
+  samples
+}
+
+fn build_fusion(config: &Config) -> Option<FusionEngine> {
+  FusionEngine::new(config.algorithm, config.effective_rate()).map(|fusion| {
+    let fusion = fusion
+      .with_ekf_noise(EkfNoise {
+        process: config.ekf_process_noise,
+        measurement: config.ekf_measurement_noise,
+      })
+      .with_gravity_source(config.gravity_source)
+      .with_gravity_direction(config.gravity_direction)
+      .with_adaptive_gain(config.adaptive_gain)
+      .with_fixed_dt(config.fixed_dt);
+
+    if config.fixed_dt && fusion.ignores_fixed_dt() {
+      eprintln!("--fixed-dt has no effect on vqf, which always runs at the nominal rate");
+    }
+
+    match config.gyro_temp_slope {
+      Some(slope) => fusion.with_gyro_temp_slope(slope, config.gyro_temp_reference),
+      None => fusion,
+    }
+  })
+}
+
+fn run_loop(
+  mut poller: Box<dyn SensorPoller>,
+  mut config: Config,
+  input: &PipelineInput,
+  metrics: &Metrics,
+  mut profiler: Option<Profiler>,
+  commands: &mpsc::Receiver<ServerCommand>,
+) -> Result<()> {
+  let mut consecutive_error_count = 0_u32;
+
+  settle_poller(&config, poller.as_mut())?;
+
   loop {
-    let sample = poller.read_sample()?;
-    let t_ms = SystemTime::now()
-      .duration_since(UNIX_EPOCH)
-      .unwrap()
-      .as_millis_f64();
-    let gravity = fusion.update(sample.accel_mps2, sample.gyro, sample.dt_sec);
-    let accel_with_gravity = sample.accel_mps2;
-    let accel_linear = [
-      accel_with_gravity[0] - gravity[0],
-      accel_with_gravity[1] - gravity[1],
-      accel_with_gravity[2] - gravity[2],
-    ];
+    if platform::shutdown_requested() {
+      return Ok(());
+    }
+
+    while let Ok(command) = commands.try_recv() {
+      poller = apply_server_command(command, poller, &mut config, input, metrics)?;
+    }
+
+    let sample = match poller.read_sample() {
+      Ok(sample) => {
+        consecutive_error_count = 0;
+
+        sample
+      }
+
+      Err(err) if err.is_interrupted() => continue,
+      Err(err) if config.max_consecutive_errors == 0 => return Err(err),
+      Err(err) => {
+        consecutive_error_count = consecutive_error_count.saturating_add(1);
+        eprintln!(
+          "{err} ({consecutive_error_count}/{} consecutive)",
+          config.max_consecutive_errors
+        );
+
+        if consecutive_error_count >= config.max_consecutive_errors {
+          return Err(Error::invalid_state_with(
+            format!(
+              "{consecutive_error_count} consecutive iio errors, exiting for supervisor restart"
+            ),
+            err,
+          ));
+        }
+
+        continue;
+      }
+    };
+    let started = Instant::now();
+
+    Metrics::inc(&metrics.samples);
+    input.push(sample);
+
+    if let Some(profiler) = profiler.as_mut() {
+      profiler.record(
+        sample.dt_sec,
+        started.elapsed(),
+        metrics.ws_rtt_us.load(Ordering::Relaxed),
+      );
+
+      if profiler.is_done() {
+        println!(
+          "{}",
+          serde_json::to_string_pretty(&profiler.report(metrics))?
+        );
+
+        return Ok(());
+      }
+    }
+  }
+}
+
+fn apply_server_command(
+  command: ServerCommand,
+  poller: Box<dyn SensorPoller>,
+  config: &mut Config,
+  input: &PipelineInput,
+  metrics: &Metrics,
+) -> Result<Box<dyn SensorPoller>> {
+  match command {
+    ServerCommand::SetRate { rate } if rate == 0 || rate == config.rate => {
+      eprintln!("server command: ignoring set_rate {rate}");
+
+      Ok(poller)
+    }
+
+    ServerCommand::SetRate { rate } => {
+      if let Err(err) = poller.check_rate(rate) {
+        eprintln!("server command: rejecting set_rate {rate}: {err}");
+
+        return Ok(poller);
+      }
+
+      let previous = config.rate;
+      let device = poller.device_id();
+
+      eprintln!("server command: set_rate {previous} -> {rate}");
+      config.rate = rate;
+
+      // An iio device hands its character device to one buffer at a time, so
+      // the old poller goes first and is reopened at the old rate on failure.
+      drop(poller);
+      let poller = match open_poller(config, device.as_deref(), metrics) {
+        Ok(poller) => poller,
+        Err(err) => {
+          eprintln!("server command: set_rate {rate} failed, restoring {previous}hz: {err}");
+          config.rate = previous;
+
+          return open_poller(config, device.as_deref(), metrics);
+        }
+      };
+
+      eprintln!("sensor: {}", poller.describe());
+      metrics.rate_hz.store(u64::from(rate), Ordering::Relaxed);
+      input.set_fusion(build_fusion(config));
+
+      if let Some(secs) = config.rms_window_secs {
+        input.set_rms(RmsAccumulator::new(rms_window_samples(secs, rate)));
+      }
+
+      Ok(poller)
+    }
+
+    ServerCommand::ResetFusion => {
+      eprintln!("server command: reset_fusion");
+      input.reset_fusion();
+
+      Ok(poller)
+    }
+  }
+}
+
+fn print_device_attrs(config: &Config, device: &str, json: bool) -> Result<()> {
+  let attrs = IioPoller::device_attrs(&iio_options(config), device)?;
+
+  if json {
+    println!("{}", serde_json::to_string_pretty(&attrs)?);
+
+    return Ok(());
+  }
+
+  for attr in &attrs {
+    println!(
+      "{} = {} ({}, {})",
+      attr.name,
+      attr.value.as_deref().unwrap_or("<unreadable>"),
+      attr.kind,
+      match attr.writable {
+        Some(true) => "rw",
+        Some(false) => "ro",
+        None => "?",
+      }
+    );
+  }
+
+  Ok(())
+}
+
+fn print_device_list(config: &Config, format: ListFormat) -> Result<()> {
+  let devices = IioPoller::list_devices(&iio_options(config))?;
+
+  if format == ListFormat::Json {
+    println!("{}", serde_json::to_string(&devices)?);
+
+    return Ok(());
+  }
+
+  let mut rows = vec![
+    [
+      "ID",
+      "NAME",
+      "GYRO",
+      "TIMESTAMP",
+      "SELECTED",
+      "ACCEL",
+      "TYPES",
+      "SCALES",
+    ]
+    .map(String::from),
+  ];
+
+  rows.extend(devices.iter().map(|device| {
+    [
+      device.id.clone().unwrap_or_else(|| "-".to_string()),
+      device.name.clone().unwrap_or_else(|| "-".to_string()),
+      yes_no(device.has_gyro),
+      yes_no(device.has_timestamp),
+      yes_no(device.is_selected),
+      device.accel_channels.join(","),
+      device
+        .sample_types
+        .iter()
+        .map(|sample_type| sample_type.map_or_else(|| "?".to_string(), |ty| format!("{ty:?}")))
+        .collect::<Vec<_>>()
+        .join(","),
+      device
+        .scales
+        .iter()
+        .map(|scale| scale.map_or_else(|| "?".to_string(), |scale| scale.to_string()))
+        .collect::<Vec<_>>()
+        .join(","),
+    ]
+  }));
+
+  let mut widths = [0; 8];
+
+  for row in &rows {
+    for (width, cell) in widths.iter_mut().zip(row) {
+      *width = (*width).max(cell.len());
+    }
+  }
+
+  for row in &rows {
+    let line = row
+      .iter()
+      .zip(widths)
+      .map(|(cell, width)| format!("{cell:<width$}"))
+      .collect::<Vec<_>>()
+      .join("  ");
+
+    println!("{}", line.trim_end());
+  }
+
+  Ok(())
+}
+
+fn yes_no(value: bool) -> String {
+  if value { "yes" } else { "no" }.to_string()
+}
+
+fn print_version_full(config: &Config) {
+  println!(
+    "{} {} ({})",
+    env!("CARGO_PKG_NAME"),
+    env!("CARGO_PKG_VERSION"),
+    env!("YURED_GIT_HASH")
+  );
+  println!("ahrs {}", env!("YURED_AHRS_VERSION"));
+  println!("vqf {}", env!("YURED_VQF_VERSION"));
+  println!("target {}-{}", std::env::consts::ARCH, std::env::consts::OS);
+
+  match IioPoller::probe(&iio_options(config)) {
+    Ok(device) => println!("device {device}"),
+    Err(err) => println!("device unavailable: {err}"),
+  }
+}
+
+fn run_sensors(
+  config: &Config,
+  queue: &Arc<SampleQueue>,
+  metrics: &Arc<Metrics>,
+  commands: mpsc::Receiver<ServerCommand>,
+) -> Result<()> {
+  if config.sensors.len() > usize::from(u8::MAX) + 1 {
+    return Err(Error::invalid_state(
+      "at most 256 --sensor devices are supported",
+    ));
+  }
+
+  if config.profile.is_some() {
+    eprintln!("multi-sensor mode: --profile is ignored");
+  }
+
+  let (done_tx, done_rx) = mpsc::channel::<Result<()>>();
+  let mut command_txs = Vec::with_capacity(config.sensors.len());
+
+  for (index, id) in (0..=u8::MAX).zip(config.sensors.iter().cloned()) {
+    let config = config.clone();
+    let queue = Arc::clone(queue);
+    let metrics = Arc::clone(metrics);
+    let done_tx = done_tx.clone();
+    let (command_tx, command_rx) = mpsc::sync_channel::<ServerCommand>(SERVER_COMMAND_QUEUE);
+
+    command_txs.push(command_tx);
 
-    queue.push_drop_old(MotionSample { accel_linear, t_ms });
+    thread::Builder::new()
+      .name(format!("sensor-{id}"))
+      .spawn(move || {
+        let result = run_sensor(config, &id, index, queue, &metrics, &command_rx);
+
+        let _ = done_tx.send(
+          result.map_err(|err| Error::invalid_state_with(format!("sensor {id} stopped"), err)),
+        );
+      })
+      .map_err(|err| Error::invalid_state_with("failed to spawn sensor thread", err))?;
+  }
+
+  drop(done_tx);
+
+  // Every sensor applies each server command to its own poller.
+  thread::spawn(move || {
+    for command in commands {
+      for command_tx in &command_txs {
+        let _ = command_tx.try_send(command);
+      }
+    }
+  });
+
+  let result = done_rx.recv().unwrap_or(Ok(()));
+
+  // Stop the other sensors too so their pollers are dropped before exiting.
+  platform::request_shutdown();
+  while done_rx.recv_timeout(SHUTDOWN_GRACE).is_ok() {}
+
+  result
+}
+
+// iio buffers are not Send, so each poller is opened on the thread that reads it.
+fn run_sensor(
+  config: Config,
+  id: &str,
+  index: u8,
+  queue: Arc<SampleQueue>,
+  metrics: &Metrics,
+  commands: &mpsc::Receiver<ServerCommand>,
+) -> Result<()> {
+  let poller = open_poller(&config, Some(id), metrics)?;
+  let fusion = build_fusion(&config);
+
+  eprintln!("sensor {id}: {}", poller.describe());
+
+  let input = build_pipeline(&config, fusion, queue)
+    .with_sensor(index)
+    .input();
+
+  spawn_fusion_state_printer(&config, &input, &format!("sensor {id} "));
+
+  run_loop(poller, config, &input, metrics, None, commands)
+}
+
+fn settle_poller(config: &Config, poller: &mut dyn SensorPoller) -> Result<()> {
+  if config.startup_delay_ms > 0 {
+    thread::sleep(Duration::from_millis(config.startup_delay_ms));
+  }
+
+  for _ in 0..config.startup_discard_samples {
+    poller.read_sample()?;
+  }
+
+  Ok(())
+}
+
+fn open_poller(
+  config: &Config,
+  device: Option<&str>,
+  metrics: &Metrics,
+) -> Result<Box<dyn SensorPoller>> {
+  let backend = if config.synthetic {
+    Backend::Synthetic
+  } else {
+    config.backend
+  };
+  let poller: Box<dyn SensorPoller> = match backend {
+    Backend::Iio => {
+      let options = IioOptions {
+        device: device.map(str::to_string),
+        trigger_jitter: metrics.trigger_jitter(),
+        nonfinite_samples: Some(Arc::clone(&metrics.nonfinite_samples)),
+        clamped_samples: Some(Arc::clone(&metrics.clamped_samples)),
+        saturated_samples: Some(Arc::clone(&metrics.saturated_samples)),
+        ..iio_options(config)
+      };
+      let poller = IioPoller::open_best(&options)?;
+
+      if config.report_device_info {
+        eprintln!(
+          "device info: {}",
+          serde_json::to_string(&poller.device_info())?
+        );
+      }
+
+      Box::new(poller)
+    }
+    Backend::Synthetic => Box::new(SyntheticImuPoller::new(config.effective_rate())),
+  };
+
+  Ok(poller)
+}
+
+fn preflight(config: &Config) -> Result<()> {
+  if config.no_preflight || config.synthetic || config.backend != Backend::Iio {
+    return Ok(());
+  }
+
+  let devices: Vec<Option<&str>> = if config.sensors.is_empty() {
+    vec![None]
+  } else {
+    config.sensors.iter().map(|id| Some(id.as_str())).collect()
+  };
+  let (fatal, warnings): (Vec<_>, Vec<_>) = devices
+    .into_iter()
+    .flat_map(|device| {
+      iio::preflight_issues(&IioOptions {
+        device: device.map(str::to_string),
+        ..iio_options(config)
+      })
+    })
+    .partition(|issue| issue.fatal);
+
+  if !warnings.is_empty() {
+    eprintln!("preflight warnings:{}", iio::preflight_report(&warnings));
+  }
+
+  if fatal.is_empty() {
+    return Ok(());
+  }
+
+  Err(Error::Preflight(iio::preflight_report(&fatal)))
+}
+
+fn build_queue(config: &Config) -> Arc<SampleQueue> {
+  let sensors = u32::try_from(config.sensors.len().max(1)).unwrap_or(u32::MAX);
+
+  Arc::new(
+    SampleQueue::new(config.queue_capacity(), config.queue_mode).with_reorder_latency(
+      Duration::from_millis(config.reorder_latency_ms),
+      f64::from(config.effective_rate()) * f64::from(sensors),
+    ),
+  )
+}
+
+fn build_metrics(config: &Config, queue: &Arc<SampleQueue>) -> Metrics {
+  let trigger_jitter = config
+    .trigger_jitter_report
+    .then(|| Arc::new(JitterHistogram::new(config.trigger_jitter_samples)));
+
+  let metrics = Metrics::default()
+    .with_trigger_jitter(trigger_jitter)
+    .with_sample_queue(Arc::clone(queue));
+
+  metrics
+    .rate_hz
+    .store(u64::from(config.effective_rate()), Ordering::Relaxed);
+
+  metrics
+}
+
+fn spawn_fusion_state_printer(config: &Config, input: &PipelineInput, prefix: &str) {
+  if config.print_fusion_state_interval_ms == 0 || config.algorithm == Algorithm::None {
+    return;
+  }
+
+  let shared = input.shared_state();
+  let interval = Duration::from_millis(config.print_fusion_state_interval_ms);
+  let prefix = prefix.to_string();
+
+  thread::spawn(move || {
+    loop {
+      thread::sleep(interval);
+
+      if shared.sample_count() == 0 {
+        continue;
+      }
+
+      let state = shared.snapshot();
+      let [w, x, y, z] = state.orientation_wxyz;
+      let [gx, gy, gz] = state.gravity;
+
+      eprintln!(
+        "{prefix}fusion state: q=[{w:.4},{x:.4},{y:.4},{z:.4}] gravity=[{gx:.3},{gy:.3},{gz:.3}] sign={} score={:.2}",
+        state.gravity_sign, state.score
+      );
+    }
+  });
+}
+
+fn spawn_queue_stats_printer(config: &Config, queue: &Arc<SampleQueue>) {
+  if config.print_queue_stats_interval_ms == 0 {
+    return;
+  }
+
+  let queue = Arc::clone(queue);
+  let interval = Duration::from_millis(config.print_queue_stats_interval_ms);
+
+  thread::spawn(move || {
+    let capacity = queue.capacity().max(1);
+    let mut last = queue.metrics();
+
+    loop {
+      thread::sleep(interval);
+
+      let snapshot = queue.metrics();
+      let pushed = snapshot.total_pushed - last.total_pushed;
+      let dropped = snapshot.total_dropped - last.total_dropped;
+
+      last = snapshot;
+
+      #[allow(clippy::cast_precision_loss)]
+      let (drop_pct, occupancy_pct) = (
+        100.0 * dropped as f64 / pushed.max(1) as f64,
+        100.0 * snapshot.current_len as f64 / capacity as f64,
+      );
+
+      eprintln!(
+        "sample queue: {pushed} pushed, {dropped} dropped ({drop_pct:.1}%) in {}ms, len {}/{capacity} ({occupancy_pct:.0}%)",
+        interval.as_millis(),
+        snapshot.current_len
+      );
+    }
+  });
+}
+
+fn spawn_pushgateway(sink: PushgatewaySink, metrics: Arc<Metrics>, interval: Duration) {
+  thread::spawn(move || {
+    loop {
+      thread::sleep(interval);
+
+      if let Err(err) = sink.push(&metrics) {
+        eprintln!("{err}");
+      }
+    }
+  });
+}
+
+fn iio_options(config: &Config) -> IioOptions {
+  IioOptions {
+    rate_hz: config.rate,
+    rate_hz_override: config.rate_hz_override,
+    sample_timeout: config.sample_timeout_ms.map(Duration::from_millis),
+    gyro_unit: config.gyro_unit,
+    accel_unit: config.accel_unit,
+    timestamp: match (&config.timestamp_channel, config.no_timestamp) {
+      (_, true) => TimestampSource::Disabled,
+      (Some(id), false) => TimestampSource::Channel(id.clone()),
+      (None, false) => TimestampSource::Auto,
+    },
+    accel_implicit_scale: config.accel_implicit_scale,
+    trigger_name: config.iio_trigger_name.clone(),
+    trigger_name_exact: config.iio_trigger_name_exact,
+    uri: config.iio_uri.clone(),
+    max_accel_mps2: config.max_accel_mps2,
+    clamp_accel: config.clamp_accel,
+    device: None,
+    trigger_jitter: None,
+    dt_kalman: config.dt_kalman,
+    sort_axes_by_scan_index: config.sort_axes_by_scan_index,
+    verbose_iio: config.verbose_iio,
+    reject_nonfinite: config.reject_nonfinite,
+    warmup_reads: config.warmup_reads,
+    nonfinite_samples: None,
+    clamped_samples: None,
+    saturated_samples: None,
+  }
+}
+
+impl Config {
+  fn validate(&self) -> Result<()> {
+    if self.output == OutputMode::Tilt && self.algorithm == Algorithm::None {
+      return Err(Error::invalid_state(
+        "--output tilt requires a fusion algorithm",
+      ));
+    }
+
+    if self.use_monotonic {
+      clock::boottime_ms()?;
+    }
+
+    for (flag, noise) in [
+      ("--ekf-process-noise", self.ekf_process_noise),
+      ("--ekf-measurement-noise", self.ekf_measurement_noise),
+    ] {
+      if !(noise.is_finite() && noise > 0.0) {
+        return Err(Error::invalid_state(format!(
+          "{flag} must be a positive finite variance"
+        )));
+      }
+    }
+
+    if self.adaptive_batch && !(1..=self.max_batch).contains(&self.min_batch) {
+      return Err(Error::invalid_state(
+        "--min-batch must be between 1 and --max-batch",
+      ));
+    }
+
+    Ok(())
+  }
+
+  // The rate samples actually arrive at, which a locked-down driver pins.
+  fn effective_rate(&self) -> u32 {
+    self.rate_hz_override.unwrap_or(self.rate)
+  }
+
+  fn queue_capacity(&self) -> usize {
+    self.batch * self.sensors.len().max(1)
+  }
+
+  fn ws_channel_depth(&self) -> usize {
+    self
+      .ws_channel_depth
+      .unwrap_or(self.batch.saturating_mul(4))
+  }
+}
+
+impl fmt::Display for Config {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut redacted = self.clone();
+
+    for url in [
+      redacted.ws_failover_url.as_mut(),
+      redacted.pushgateway_url.as_mut(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+      if url.password().is_some() {
+        let _ = url.set_password(Some("redacted"));
+      }
+    }
+
+    write!(f, "{redacted:#?}")
   }
 }
 
 fn sender_loop(
   config: &Config,
+  names: &FieldNames,
   yure_id: &str,
   queue: &Arc<SampleQueue>,
   user_agent: &str,
   tx: &mpsc::SyncSender<String>,
+  metrics: &Metrics,
 ) {
-  let mut batch = StreamBatcher::new(config.batch);
+  let mut batch = StreamBatcher::new(config.batch, yure_id, user_agent)
+    .with_strategy(
+      config
+        .batch_strategy
+        .unwrap_or(BatchStrategy::Size(config.batch)),
+    )
+    .with_envelope_version(config.envelope_version)
+    .with_crc(config.batch_crc)
+    .with_stats(config.include_batch_stats)
+    .with_units(config.include_units);
+  let heartbeat = config.heartbeat_ms.map(Duration::from_millis);
+  let rms_interval = Duration::from_millis(config.rms_interval_ms);
+  let mut csv = config
+    .output_csv
+    .then(|| CsvWriter::new(io::BufWriter::new(io::stdout()), yure_id, user_agent));
+  let mut last_sample_at = Instant::now();
+  let mut last_rms_at = Instant::now();
 
   loop {
-    let motion = queue.pop_wait();
-    let sample = YureSample {
-      yure_id,
-      user_agent,
-      x: motion.accel_linear[0],
-      y: motion.accel_linear[1],
-      z: motion.accel_linear[2],
-      t: motion.t_ms,
+    let batch_seq = batch.batch_seq();
+    let wake_at = heartbeat
+      .map(|interval| last_sample_at + interval)
+      .into_iter()
+      .chain(batch.deadline())
+      .chain(queue.stall_deadline())
+      .min();
+    let mut motions = match wake_at {
+      Some(at) => queue.drain_available_timeout(at.saturating_duration_since(Instant::now())),
+      None => queue.drain_available_blocking(),
     };
 
-    if config.verbose
-      && let Ok(line) = serde_json::to_string(&sample)
-    {
-      println!("{line}");
+    if motions.is_empty() {
+      motions = queue.drain_stalled(Instant::now());
     }
 
-    match batch.push_sample(sample) {
-      Ok(Some(json)) => {
-        let _ = tx.try_send(json);
+    if !motions.is_empty() {
+      last_sample_at = Instant::now();
+    } else if heartbeat.is_some_and(|interval| last_sample_at.elapsed() >= interval) {
+      last_sample_at = Instant::now();
+      motions.push(MotionSample {
+        t_ms: sample_clock_ms(config.use_monotonic),
+        heartbeat: true,
+        ..MotionSample::default()
+      });
+    } else {
+      match batch.flush_due(Instant::now()) {
+        Ok(Some(json)) => send_batch(json, tx, metrics),
+        Ok(None) => {}
+        Err(err) => eprintln!("{err}"),
       }
-      Ok(None) => {}
-      Err(err) => {
+    }
+
+    if config.adaptive_batch {
+      resize_batch(config, &mut batch, tx, metrics);
+    }
+
+    for motion in &motions {
+      let mut sample = yure_sample(config, names, motion);
+
+      if let Some(rms) = motion.rms
+        && last_rms_at.elapsed() >= rms_interval
+      {
+        last_rms_at = Instant::now();
+        metrics.set_vibration_rms(rms);
+        sample.rms = Some(rms);
+      }
+
+      if config.verbose
+        && let Some(temp_c) = motion.temp_c
+      {
+        eprintln!("temp: {temp_c:.2}c");
+      }
+
+      if config.verbose {
+        print_sample_json(&sample, config.output_csv);
+      }
+
+      if let Some(csv) = csv.as_mut()
+        && let Err(err) = csv.write_sample(&sample)
+      {
         eprintln!("{err}");
       }
+
+      match batch.push_sample(&sample, Instant::now()) {
+        Ok(Some(json)) => send_batch(json, tx, metrics),
+        Ok(None) => {}
+        Err(err) => {
+          eprintln!("{err}");
+        }
+      }
+    }
+
+    // CSV rows reach stdout in the same groups the batches are sent in.
+    if batch.batch_seq() != batch_seq
+      && let Some(csv) = csv.as_mut()
+      && let Err(err) = csv.flush()
+    {
+      eprintln!("{err}");
     }
   }
 }
 
-fn ws_loop(url: url::Url, rx: &mpsc::Receiver<String>) {
-  let mut ws = WsClient::new(url);
+fn adaptive_batch_size(config: &Config, rate_hz: u64, rtt_us: u64) -> usize {
+  let target = rtt_us.saturating_mul(rate_hz) / 1_000_000;
+
+  usize::try_from(target)
+    .unwrap_or(usize::MAX)
+    .clamp(config.min_batch, config.max_batch)
+}
+
+fn resize_batch(
+  config: &Config,
+  batch: &mut StreamBatcher<'_>,
+  tx: &mpsc::SyncSender<String>,
+  metrics: &Metrics,
+) {
+  let rtt_us = metrics.ws_rtt_us.load(Ordering::Relaxed);
+
+  if rtt_us == 0 {
+    return;
+  }
+
+  let target = adaptive_batch_size(config, metrics.rate_hz.load(Ordering::Relaxed), rtt_us);
+
+  if batch.batch_size().is_none_or(|size| size == target) {
+    return;
+  }
+
+  if config.verbose {
+    eprintln!("websocket rtt {}ms, batch size {target}", rtt_us / 1000);
+  }
+
+  match batch.resize(target, Instant::now()) {
+    Ok(Some(json)) => send_batch(json, tx, metrics),
+    Ok(None) => {}
+    Err(err) => eprintln!("{err}"),
+  }
+}
+
+fn send_batch(json: String, tx: &mpsc::SyncSender<String>, metrics: &Metrics) {
+  Metrics::inc(&metrics.batches);
+  Metrics::add(&metrics.batch_bytes, json.len() as u64);
+
+  if tx.try_send(json).is_err() {
+    Metrics::inc(&metrics.batches_dropped);
+  }
+}
+
+fn print_sample_json(sample: &YureSample<'_>, to_stderr: bool) {
+  let mut out: Box<dyn Write> = if to_stderr {
+    Box::new(io::stderr().lock())
+  } else {
+    Box::new(io::stdout().lock())
+  };
+
+  if serde_json::to_writer(&mut out, sample).is_ok() {
+    let _ = writeln!(out);
+  }
+}
+
+fn yure_sample<'a>(
+  config: &'a Config,
+  names: &'a FieldNames,
+  motion: &MotionSample,
+) -> YureSample<'a> {
+  let [x, y, z] = match (config.output, motion.jerk) {
+    (OutputMode::Jerk, Some(jerk)) => jerk,
+    _ => motion.accel,
+  };
+
+  YureSample {
+    names,
+    sensor: motion
+      .sensor
+      .and_then(|index| config.sensors.get(usize::from(index)))
+      .map(String::as_str),
+    axes: config.axes,
+    x,
+    y,
+    z,
+    m: config
+      .output_magnitude
+      .then(|| (x * x + y * y + z * z).sqrt()),
+    raw: config.include_raw.then_some(motion.accel_total),
+    gyro: motion.gyro.filter(|_| config.algorithm == Algorithm::None),
+    gyro_axes: config.include_gyro.then_some(motion.gyro),
+    tilt: motion.tilt.filter(|_| config.output == OutputMode::Tilt),
+    t: motion.t_ms,
+    accel_raw: motion.accel_raw.filter(|_| config.emit_raw),
+    gyro_raw: motion.gyro_raw.filter(|_| config.emit_raw),
+    q: config.include_quality.then_some(motion.quality),
+    rms: None,
+    heartbeat: motion.heartbeat,
+  }
+}
+
+fn ws_loop(
+  config: &Config,
+  url: url::Url,
+  rx: &mpsc::Receiver<String>,
+  commands: &mpsc::SyncSender<ServerCommand>,
+  metrics: &Metrics,
+) {
+  let client = |url| {
+    let client = WsClient::new(url)
+      .with_commands(commands.clone())
+      .with_max_rejections(config.ws_max_rejections)
+      .with_timeouts(
+        Duration::from_millis(config.ws_read_timeout_ms.max(1)),
+        Duration::from_millis(config.ws_write_timeout_ms.max(1)),
+      )
+      .with_max_message_size(config.ws_max_message_size)
+      .with_max_frame_bytes(config.ws_max_frame_kb.saturating_mul(1024));
+
+    if config.adaptive_batch {
+      client.with_keepalive(
+        Duration::from_millis(config.ws_ping_interval_ms.max(1)),
+        Arc::clone(&metrics.ws_rtt_us),
+      )
+    } else {
+      client
+    }
+  };
+  let primary = client(url);
+  let failover = config.ws_failover_url.clone().map(client);
+  let mut ws = FailoverWsClient::new(
+    primary,
+    failover,
+    config.ws_failover_after,
+    Duration::from_secs(config.ws_primary_recover_after),
+  );
+  let mut limiter = SendRateLimiter::new(config.ws_send_rate_hz);
 
   loop {
     if !ws.is_connected()
       && let Err(err) = ws.poll_connect()
     {
       eprintln!("{err}");
-      thread::sleep(Duration::from_millis(200));
+
+      if ws.is_circuit_open() {
+        eprintln!(
+          "websocket rejected {} times in a row, pausing reconnects for {}s",
+          config.ws_max_rejections, config.ws_circuit_open_secs
+        );
+        thread::sleep(Duration::from_secs(config.ws_circuit_open_secs));
+        ws.half_open_circuit();
+      } else {
+        thread::sleep(Duration::from_millis(200));
+      }
 
       continue;
     }
 
     match rx.recv_timeout(Duration::from_millis(10)) {
       Ok(json) => {
-        if let Err(err) = ws.send_text(json) {
-          eprintln!("{err}");
+        while let Some(wait) = limiter.try_consume() {
+          thread::sleep(wait);
+        }
+
+        match ws.send_text(json) {
+          Ok(true) => Metrics::inc(&metrics.ws_sent),
+          Ok(false) => {}
+          Err(err) => {
+            Metrics::inc(&metrics.ws_errors);
+            eprintln!("{err}");
+          }
         }
       }
       Err(mpsc::RecvTimeoutError::Timeout) => {}
       Err(mpsc::RecvTimeoutError::Disconnected) => break,
     }
 
-    if let Err(err) = ws.poll_incoming() {
+    if let Err(err) = ws.poll_keepalive(Instant::now()) {
+      Metrics::inc(&metrics.ws_errors);
       eprintln!("{err}");
     }
-  }
-}
 
-impl SampleQueue {
-  fn new(cap: usize) -> Self {
-    Self {
-      queue: Mutex::new(AllocRingBuffer::new(cap)),
-      not_empty: Condvar::new(),
+    if let Err(err) = ws.poll_incoming() {
+      eprintln!("{err}");
     }
   }
+}
 
-  fn push_drop_old(&self, item: MotionSample) {
-    let mut guard = self.queue.lock().unwrap();
-    let _ = guard.enqueue(item);
-
-    self.not_empty.notify_one();
-  }
-
-  fn pop_wait(&self) -> MotionSample {
-    let mut guard = self.queue.lock().unwrap();
+#[cfg(test)]
+mod tests {
+  use super::{Config, adaptive_batch_size};
+  use clap::Parser;
 
-    loop {
-      if let Some(item) = guard.dequeue() {
-        return item;
-      }
+  #[test]
+  fn adaptive_batch_size_follows_rtt_within_bounds() {
+    let config = Config::try_parse_from([
+      "yured",
+      "--adaptive-batch",
+      "--min-batch",
+      "5",
+      "--max-batch",
+      "60",
+    ])
+    .unwrap();
 
-      guard = self.not_empty.wait(guard).unwrap();
-    }
+    // 200 Hz over a 100 ms round trip is 20 samples in flight.
+    assert_eq!(adaptive_batch_size(&config, 200, 100_000), 20);
+    assert_eq!(adaptive_batch_size(&config, 200, 1_000), 5);
+    assert_eq!(adaptive_batch_size(&config, 200, 10_000_000), 60);
+    assert_eq!(adaptive_batch_size(&config, 0, 100_000), 5);
+    assert_eq!(adaptive_batch_size(&config, u64::MAX, u64::MAX), 60);
   }
 }
@@ -0,0 +1,270 @@
+use crate::error::{Error, Result};
+use crate::iio::JitterHistogram;
+use crate::pipeline::SampleQueue;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use url::Url;
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+  pub samples: AtomicU64,
+  pub batches: AtomicU64,
+  pub batch_bytes: AtomicU64,
+  pub batches_dropped: AtomicU64,
+  pub ws_sent: AtomicU64,
+  pub ws_errors: AtomicU64,
+  pub nonfinite_samples: Arc<AtomicU64>,
+  pub clamped_samples: Arc<AtomicU64>,
+  pub saturated_samples: Arc<AtomicU64>,
+  pub ws_rtt_us: Arc<AtomicU64>,
+  pub rate_hz: AtomicU64,
+  vibration_rms_bits: AtomicU64,
+  trigger_jitter: Option<Arc<JitterHistogram>>,
+  sample_queue: Option<Arc<SampleQueue>>,
+}
+
+pub struct PushgatewaySink {
+  url: Url,
+  instance: String,
+}
+
+impl Metrics {
+  pub fn with_trigger_jitter(mut self, trigger_jitter: Option<Arc<JitterHistogram>>) -> Self {
+    self.trigger_jitter = trigger_jitter;
+    self
+  }
+
+  pub fn with_sample_queue(mut self, sample_queue: Arc<SampleQueue>) -> Self {
+    self.sample_queue = Some(sample_queue);
+    self
+  }
+
+  pub fn trigger_jitter(&self) -> Option<Arc<JitterHistogram>> {
+    self.trigger_jitter.clone()
+  }
+
+  pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+  }
+
+  pub fn set_vibration_rms(&self, rms: f64) {
+    self
+      .vibration_rms_bits
+      .store(rms.to_bits(), Ordering::Relaxed);
+  }
+
+  pub fn vibration_rms(&self) -> f64 {
+    f64::from_bits(self.vibration_rms_bits.load(Ordering::Relaxed))
+  }
+
+  pub fn render(&self) -> String {
+    let counters = [
+      ("yured_samples_total", "imu samples read", &self.samples),
+      ("yured_batches_total", "batches encoded", &self.batches),
+      (
+        "yured_batches_dropped_total",
+        "batches dropped because the send queue was full",
+        &self.batches_dropped,
+      ),
+      (
+        "yured_ws_sent_total",
+        "batches sent over websocket",
+        &self.ws_sent,
+      ),
+      (
+        "yured_ws_errors_total",
+        "websocket send errors",
+        &self.ws_errors,
+      ),
+      (
+        "yured_nonfinite_samples_total",
+        "accel samples with nan or inf components",
+        &*self.nonfinite_samples,
+      ),
+      (
+        "yured_clamped_samples_total",
+        "accel samples clamped to --max-accel-mps2",
+        &*self.clamped_samples,
+      ),
+      (
+        "yured_saturated_samples_total",
+        "accel samples at the end of the channel's raw range",
+        &*self.saturated_samples,
+      ),
+    ];
+    let mut out = String::new();
+
+    for (name, help, counter) in counters {
+      let _ = writeln!(out, "# HELP {name} {help}");
+      let _ = writeln!(out, "# TYPE {name} counter");
+      let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+    }
+
+    let _ = writeln!(
+      out,
+      "# HELP yured_vibration_rms_mps2 windowed rms of linear accel magnitude"
+    );
+    let _ = writeln!(out, "# TYPE yured_vibration_rms_mps2 gauge");
+    let _ = writeln!(out, "yured_vibration_rms_mps2 {}", self.vibration_rms());
+
+    let _ = writeln!(
+      out,
+      "# HELP yured_rate_hz sampling rate currently requested from the sensor"
+    );
+    let _ = writeln!(out, "# TYPE yured_rate_hz gauge");
+    let _ = writeln!(
+      out,
+      "yured_rate_hz {}",
+      self.rate_hz.load(Ordering::Relaxed)
+    );
+
+    let _ = writeln!(
+      out,
+      "# HELP yured_ws_rtt_microseconds latest websocket ping round trip, 0 before the first pong"
+    );
+    let _ = writeln!(out, "# TYPE yured_ws_rtt_microseconds gauge");
+    let _ = writeln!(
+      out,
+      "yured_ws_rtt_microseconds {}",
+      self.ws_rtt_us.load(Ordering::Relaxed)
+    );
+
+    if let Some(queue) = self.sample_queue.as_ref() {
+      let snapshot = queue.metrics();
+
+      for (name, help, value) in [
+        (
+          "yured_sample_queue_pushed_total",
+          "samples pushed to the sender queue",
+          snapshot.total_pushed,
+        ),
+        (
+          "yured_sample_queue_dropped_total",
+          "samples evicted from the full sender queue",
+          snapshot.total_dropped,
+        ),
+        (
+          "yured_sample_queue_popped_total",
+          "samples taken by the sender",
+          snapshot.total_popped,
+        ),
+      ] {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {value}");
+      }
+
+      let _ = writeln!(
+        out,
+        "# HELP yured_sample_queue_len samples waiting in the sender queue"
+      );
+      let _ = writeln!(out, "# TYPE yured_sample_queue_len gauge");
+      let _ = writeln!(out, "yured_sample_queue_len {}", snapshot.current_len);
+    }
+
+    if let Some(trigger_jitter) = self.trigger_jitter.as_ref() {
+      trigger_jitter.render(&mut out);
+    }
+
+    out
+  }
+}
+
+impl PushgatewaySink {
+  pub fn new(url: Url, instance: &str) -> Result<Self> {
+    if url.scheme() != "http" {
+      return Err(Error::invalid_state(format!(
+        "pushgateway url {url} must use http"
+      )));
+    }
+
+    if url.host_str().is_none() {
+      return Err(Error::invalid_state(format!(
+        "pushgateway url {url} has no host"
+      )));
+    }
+
+    Ok(Self {
+      url,
+      instance: instance.to_string(),
+    })
+  }
+
+  pub fn push(&self, metrics: &Metrics) -> Result<()> {
+    let host = self.url.host_str().unwrap_or_default();
+    let port = self.url.port_or_known_default().unwrap_or(80);
+    let path = format!(
+      "{}/metrics/job/yured/instance/{}",
+      self.url.path().trim_end_matches('/'),
+      self.instance
+    );
+    let body = metrics.render();
+    let request = format!(
+      "PUT {path} HTTP/1.1\r\nHost: {host}:{port}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+      body.len()
+    );
+    let mut stream = self.connect()?;
+
+    stream
+      .set_read_timeout(Some(PUSH_TIMEOUT))
+      .and_then(|()| stream.set_write_timeout(Some(PUSH_TIMEOUT)))
+      .and_then(|()| stream.write_all(request.as_bytes()))
+      .map_err(|err| Error::invalid_state_with("failed to send pushgateway request", err))?;
+
+    let mut response = String::new();
+
+    stream
+      .read_to_string(&mut response)
+      .map_err(|err| Error::invalid_state_with("failed to read pushgateway response", err))?;
+
+    let status = response
+      .split_whitespace()
+      .nth(1)
+      .and_then(|code| code.parse::<u16>().ok())
+      .unwrap_or(0);
+
+    if (200..300).contains(&status) {
+      return Ok(());
+    }
+
+    let body = response
+      .split_once("\r\n\r\n")
+      .map_or("", |(_, body)| body.trim());
+
+    Err(Error::invalid_state(format!(
+      "pushgateway rejected metrics: http {status}: {body}"
+    )))
+  }
+
+  // Resolves through the url so IPv6 literals lose their brackets, and bounds
+  // each attempt so an unreachable gateway cannot stall the push thread.
+  fn connect(&self) -> Result<TcpStream> {
+    let addrs = self
+      .url
+      .socket_addrs(|| Some(80))
+      .map_err(|err| Error::invalid_state_with("failed to resolve pushgateway host", err))?;
+    let mut last_err = None;
+
+    for addr in addrs {
+      match TcpStream::connect_timeout(&addr, PUSH_TIMEOUT) {
+        Ok(stream) => return Ok(stream),
+        Err(err) => last_err = Some(err),
+      }
+    }
+
+    Err(match last_err {
+      Some(err) => Error::invalid_state_with("failed to connect to pushgateway", err),
+      None => Error::invalid_state("pushgateway host resolved to no addresses"),
+    })
+  }
+}
@@ -0,0 +1,931 @@
+use crate::clock::boottime_ms;
+use crate::fusion::{FusionEngine, Tilt};
+use crate::sensor::ImuSample;
+use clap::ValueEnum;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::BinaryHeap;
+use std::f64::consts::TAU;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const IDLE_DOWNCLOCK_FACTOR: u32 = 10;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MotionSample {
+  pub accel: [f64; 3],
+  pub accel_total: [f64; 3],
+  pub gyro: Option<[f64; 3]>,
+  pub t_ms: f64,
+  pub accel_raw: Option<[i64; 3]>,
+  pub gyro_raw: Option<[i64; 3]>,
+  pub quality: f64,
+  pub tilt: Option<Tilt>,
+  pub jerk: Option<[f64; 3]>,
+  pub rms: Option<f64>,
+  pub sensor: Option<u8>,
+  pub temp_c: Option<f64>,
+  pub heartbeat: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum SampleQueueMode {
+  #[default]
+  Fifo,
+  Timestamp,
+}
+
+#[derive(Debug)]
+pub struct SampleQueue {
+  queue: Mutex<QueueStorage>,
+  not_empty: Condvar,
+  metrics: Arc<Mutex<SampleQueueMetrics>>,
+}
+
+#[derive(Debug, Default)]
+pub struct SampleQueueMetrics {
+  total_pushed: u64,
+  total_dropped: u64,
+  total_popped: u64,
+  current_len: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SampleQueueMetricsSnapshot {
+  pub total_pushed: u64,
+  pub total_dropped: u64,
+  pub total_popped: u64,
+  pub current_len: usize,
+}
+
+#[derive(Debug)]
+enum QueueStorage {
+  Fifo(AllocRingBuffer<MotionSample>),
+  Timestamp {
+    heap: BinaryHeap<Reverse<ByTimestamp>>,
+    cap: usize,
+    hold_back: Duration,
+    newest_ms: f64,
+    last_push_at: Option<Instant>,
+  },
+}
+
+#[derive(Debug)]
+struct ByTimestamp(MotionSample);
+
+// Reported to the handler given to Pipeline::with_event_handler; the library
+// itself never logs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PipelineEvent {
+  IdleEntered { still_secs: f64 },
+  IdleExited,
+  AccelSaturated { accel_mps2: [f64; 3], count: u64 },
+}
+
+pub struct Pipeline {
+  fusion: Option<FusionEngine>,
+  queue: Arc<SampleQueue>,
+  idle: Option<IdleDownclock>,
+  jerk: Option<JerkDifferentiator>,
+  rms: Option<RmsAccumulator>,
+  sensor: Option<u8>,
+  monotonic: bool,
+  shared: Arc<SharedFusionState>,
+  on_event: Option<Box<dyn FnMut(PipelineEvent) + Send>>,
+}
+
+// Snapshot of the latest fused values for threads that must not touch the
+// FusionEngine itself, such as a d-bus interface.
+#[derive(Debug, Default)]
+pub struct SharedFusionState {
+  latest: Mutex<FusionSnapshot>,
+  sample_count: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FusionSnapshot {
+  pub accel_linear: [f64; 3],
+  pub orientation_wxyz: [f64; 4],
+  pub gravity: [f64; 3],
+  pub gravity_sign: &'static str,
+  pub score: f64,
+}
+
+pub struct RmsAccumulator {
+  window: AllocRingBuffer<f64>,
+  sum_sq: f64,
+  since_resum: usize,
+}
+
+pub struct JerkDifferentiator {
+  cutoff_hz: Option<f64>,
+  prev: Option<[f64; 3]>,
+  jerk: [f64; 3],
+}
+
+pub struct IdleDownclock {
+  threshold_mps2: f64,
+  after_secs: f64,
+  still_secs: f64,
+  last_accel: Option<[f64; 3]>,
+  idle: bool,
+  skipped: u32,
+  pending_dt: f64,
+}
+
+#[derive(Clone)]
+pub struct PipelineInput {
+  pipeline: Arc<Mutex<Pipeline>>,
+  shared: Arc<SharedFusionState>,
+}
+
+impl SampleQueue {
+  #[must_use]
+  pub fn new(cap: usize, mode: SampleQueueMode) -> Self {
+    let storage = match mode {
+      SampleQueueMode::Fifo => QueueStorage::Fifo(AllocRingBuffer::new(cap)),
+      SampleQueueMode::Timestamp => QueueStorage::Timestamp {
+        heap: BinaryHeap::with_capacity(cap),
+        cap: cap.max(1),
+        hold_back: Duration::ZERO,
+        newest_ms: f64::NEG_INFINITY,
+        last_push_at: None,
+      },
+    };
+
+    Self {
+      queue: Mutex::new(storage),
+      not_empty: Condvar::new(),
+      metrics: Arc::default(),
+    }
+  }
+
+  // Timestamp mode keeps each sample until one at least `latency` newer has
+  // arrived, so a sensor thread that pushes late still lands in order. The
+  // capacity grows by the samples that fit in that window at
+  // `samples_per_sec`, so held samples are not evicted before release.
+  #[must_use]
+  pub fn with_reorder_latency(mut self, latency: Duration, samples_per_sec: f64) -> Self {
+    if let QueueStorage::Timestamp { cap, hold_back, .. } = self.queue.get_mut().unwrap() {
+      #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+      let window = (samples_per_sec.max(0.0) * latency.as_secs_f64()).ceil() as usize;
+
+      *cap += window;
+      *hold_back = latency;
+    }
+
+    self
+  }
+
+  // When held samples will be flushed if no newer one arrives to release them.
+  pub fn stall_deadline(&self) -> Option<Instant> {
+    self.queue.lock().unwrap().stall_deadline()
+  }
+
+  // Releases every held sample once pushes have stopped for the reorder window.
+  pub fn drain_stalled(&self, now: Instant) -> Vec<MotionSample> {
+    let mut guard = self.queue.lock().unwrap();
+
+    if guard.stall_deadline().is_none_or(|deadline| now < deadline) {
+      return Vec::new();
+    }
+
+    let items = guard.drain_all();
+
+    self.record_drain(items, guard.len())
+  }
+
+  pub fn push_drop_old(&self, item: &MotionSample) {
+    let mut guard = self.queue.lock().unwrap();
+    let dropped = guard.push(item);
+    let mut metrics = self.metrics.lock().unwrap();
+
+    metrics.total_pushed += 1;
+    metrics.total_dropped += u64::from(dropped);
+    metrics.current_len = guard.len();
+    drop(metrics);
+
+    self.not_empty.notify_one();
+  }
+
+  pub fn capacity(&self) -> usize {
+    match &*self.queue.lock().unwrap() {
+      QueueStorage::Fifo(ring) => ring.capacity(),
+      QueueStorage::Timestamp { cap, .. } => *cap,
+    }
+  }
+
+  pub fn metrics(&self) -> SampleQueueMetricsSnapshot {
+    let metrics = self.metrics.lock().unwrap();
+
+    SampleQueueMetricsSnapshot {
+      total_pushed: metrics.total_pushed,
+      total_dropped: metrics.total_dropped,
+      total_popped: metrics.total_popped,
+      current_len: metrics.current_len,
+    }
+  }
+
+  pub fn drain_available(&self) -> Vec<MotionSample> {
+    let mut guard = self.queue.lock().unwrap();
+    let items = guard.drain();
+
+    self.record_drain(items, guard.len())
+  }
+
+  pub fn drain_available_blocking(&self) -> Vec<MotionSample> {
+    let guard = self.queue.lock().unwrap();
+    let mut guard = self
+      .not_empty
+      .wait_while(guard, |queue| !queue.has_ready())
+      .unwrap();
+    let items = guard.drain();
+
+    self.record_drain(items, guard.len())
+  }
+
+  // An empty result means the timeout passed first.
+  pub fn drain_available_timeout(&self, timeout: Duration) -> Vec<MotionSample> {
+    let guard = self.queue.lock().unwrap();
+    let (mut guard, _) = self
+      .not_empty
+      .wait_timeout_while(guard, timeout, |queue| !queue.has_ready())
+      .unwrap();
+    let items = guard.drain();
+
+    self.record_drain(items, guard.len())
+  }
+
+  fn record_drain(&self, items: Vec<MotionSample>, remaining: usize) -> Vec<MotionSample> {
+    let mut metrics = self.metrics.lock().unwrap();
+
+    metrics.total_popped += items.len() as u64;
+    metrics.current_len = remaining;
+
+    items
+  }
+}
+
+impl QueueStorage {
+  // In timestamp mode a full queue evicts whichever sample is oldest by t_ms,
+  // which may be the incoming one when a slow sensor delivers late.
+  // Returns whether a sample was dropped to make room.
+  fn push(&mut self, item: &MotionSample) -> bool {
+    match self {
+      Self::Fifo(ring) => ring.enqueue(*item).is_some(),
+      Self::Timestamp {
+        heap,
+        cap,
+        newest_ms,
+        last_push_at,
+        ..
+      } => {
+        let full = heap.len() >= *cap;
+
+        *newest_ms = newest_ms.max(item.t_ms);
+        *last_push_at = Some(Instant::now());
+
+        if full {
+          match heap.peek() {
+            Some(Reverse(oldest)) if oldest.0.t_ms > item.t_ms => return true,
+            _ => {
+              heap.pop();
+            }
+          }
+        }
+
+        heap.push(Reverse(ByTimestamp(*item)));
+
+        full
+      }
+    }
+  }
+
+  fn len(&self) -> usize {
+    match self {
+      Self::Fifo(ring) => ring.len(),
+      Self::Timestamp { heap, .. } => heap.len(),
+    }
+  }
+
+  fn drain(&mut self) -> Vec<MotionSample> {
+    match self {
+      Self::Fifo(ring) => ring.drain().collect(),
+      Self::Timestamp {
+        heap,
+        hold_back,
+        newest_ms,
+        ..
+      } => {
+        let release_until = *newest_ms - hold_back.as_millis_f64();
+        let mut items = Vec::new();
+
+        while heap
+          .peek()
+          .is_some_and(|Reverse(oldest)| oldest.0.t_ms <= release_until)
+        {
+          let Some(Reverse(ByTimestamp(item))) = heap.pop() else {
+            break;
+          };
+
+          items.push(item);
+        }
+
+        items
+      }
+    }
+  }
+
+  fn has_ready(&self) -> bool {
+    match self {
+      Self::Fifo(ring) => !ring.is_empty(),
+      Self::Timestamp {
+        heap,
+        hold_back,
+        newest_ms,
+        ..
+      } => heap
+        .peek()
+        .is_some_and(|Reverse(oldest)| oldest.0.t_ms <= *newest_ms - hold_back.as_millis_f64()),
+    }
+  }
+
+  fn stall_deadline(&self) -> Option<Instant> {
+    match self {
+      Self::Fifo(_) => None,
+      Self::Timestamp {
+        heap,
+        hold_back,
+        last_push_at,
+        ..
+      } => last_push_at
+        .filter(|_| !heap.is_empty())
+        .map(|at| at + *hold_back),
+    }
+  }
+
+  fn drain_all(&mut self) -> Vec<MotionSample> {
+    match self {
+      Self::Fifo(ring) => ring.drain().collect(),
+      Self::Timestamp { heap, .. } => {
+        let mut items = std::mem::take(heap).into_sorted_vec();
+
+        // Reverse ordering sorts newest first.
+        items.reverse();
+        items
+          .into_iter()
+          .map(|Reverse(ByTimestamp(item))| item)
+          .collect()
+      }
+    }
+  }
+}
+
+impl PartialEq for ByTimestamp {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == CmpOrdering::Equal
+  }
+}
+
+impl Eq for ByTimestamp {}
+
+impl PartialOrd for ByTimestamp {
+  fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for ByTimestamp {
+  fn cmp(&self, other: &Self) -> CmpOrdering {
+    self.0.t_ms.total_cmp(&other.0.t_ms)
+  }
+}
+
+impl Pipeline {
+  pub fn new(fusion: Option<FusionEngine>, queue: Arc<SampleQueue>) -> Self {
+    Self {
+      fusion,
+      queue,
+      idle: None,
+      jerk: None,
+      rms: None,
+      sensor: None,
+      monotonic: false,
+      shared: Arc::new(SharedFusionState::default()),
+      on_event: None,
+    }
+  }
+
+  #[must_use]
+  pub fn with_monotonic(mut self, monotonic: bool) -> Self {
+    self.monotonic = monotonic;
+    self
+  }
+
+  #[must_use]
+  pub fn with_sensor(mut self, sensor: u8) -> Self {
+    self.sensor = Some(sensor);
+    self
+  }
+
+  #[must_use]
+  pub fn with_rms(mut self, rms: RmsAccumulator) -> Self {
+    self.rms = Some(rms);
+    self
+  }
+
+  #[must_use]
+  pub fn with_jerk(mut self, jerk: JerkDifferentiator) -> Self {
+    self.jerk = Some(jerk);
+    self
+  }
+
+  #[must_use]
+  pub fn with_idle_downclock(mut self, idle: IdleDownclock) -> Self {
+    self.idle = Some(idle);
+    self
+  }
+
+  #[must_use]
+  pub fn with_event_handler(
+    mut self,
+    on_event: impl FnMut(PipelineEvent) + Send + 'static,
+  ) -> Self {
+    self.on_event = Some(Box::new(on_event));
+    self
+  }
+
+  pub fn set_fusion(&mut self, fusion: Option<FusionEngine>) {
+    self.fusion = fusion;
+  }
+
+  pub fn set_rms(&mut self, rms: RmsAccumulator) {
+    self.rms = Some(rms);
+  }
+
+  pub fn reset_fusion(&mut self) {
+    if let Some(fusion) = self.fusion.as_mut() {
+      fusion.reset();
+    }
+  }
+
+  #[must_use]
+  pub fn input(self) -> PipelineInput {
+    PipelineInput {
+      shared: Arc::clone(&self.shared),
+      pipeline: Arc::new(Mutex::new(self)),
+    }
+  }
+
+  pub fn step(&mut self, sample: ImuSample) {
+    let (sample, periods) = match self.idle.as_mut() {
+      Some(idle) => {
+        let was_idle = idle.idle;
+        let admitted = idle.admit(&sample);
+
+        if idle.idle != was_idle {
+          let event = if idle.idle {
+            PipelineEvent::IdleEntered {
+              still_secs: idle.still_secs,
+            }
+          } else {
+            PipelineEvent::IdleExited
+          };
+
+          self.notify(event);
+        }
+
+        let Some((dt_sec, periods)) = admitted else {
+          return;
+        };
+
+        (ImuSample { dt_sec, ..sample }, periods)
+      }
+
+      None => (sample, 1),
+    };
+    let t_ms = sample_clock_ms(self.monotonic);
+
+    let Some(fusion) = self.fusion.as_mut() else {
+      let motion = MotionSample {
+        accel: sample.accel_mps2,
+        accel_total: sample.accel_mps2,
+        gyro: sample.gyro,
+        t_ms,
+        accel_raw: sample.accel_raw,
+        gyro_raw: sample.gyro_raw,
+        quality: 1.0,
+        tilt: None,
+        jerk: None,
+        rms: None,
+        sensor: None,
+        temp_c: sample.temp_c,
+        heartbeat: false,
+      };
+
+      self.emit(motion, sample.dt_sec);
+
+      return;
+    };
+
+    let saturated_before = fusion.saturated_updates();
+    let gravity = fusion.update(&sample, periods);
+
+    if fusion.saturated_updates() != saturated_before
+      && let Some(on_event) = self.on_event.as_mut()
+    {
+      on_event(PipelineEvent::AccelSaturated {
+        accel_mps2: sample.accel_mps2,
+        count: fusion.saturated_updates(),
+      });
+    }
+    let accel_with_gravity = sample.accel_mps2;
+    let accel_linear = [
+      accel_with_gravity[0] - gravity[0],
+      accel_with_gravity[1] - gravity[1],
+      accel_with_gravity[2] - gravity[2],
+    ];
+
+    self.shared.record(FusionSnapshot {
+      accel_linear,
+      orientation_wxyz: fusion.orientation_wxyz(),
+      gravity,
+      gravity_sign: fusion.gravity_sign(),
+      score: fusion.convergence_score(),
+    });
+
+    let motion = MotionSample {
+      accel: accel_linear,
+      accel_total: sample.accel_mps2,
+      gyro: sample
+        .gyro
+        .map(|gyro| fusion.compensate_gyro(gyro, sample.temp_c)),
+      t_ms,
+      accel_raw: sample.accel_raw,
+      gyro_raw: sample.gyro_raw,
+      quality: fusion.convergence_score(),
+      tilt: Some(fusion.tilt()),
+      jerk: None,
+      rms: None,
+      sensor: None,
+      temp_c: sample.temp_c,
+      heartbeat: false,
+    };
+
+    self.emit(motion, sample.dt_sec);
+  }
+
+  fn notify(&mut self, event: PipelineEvent) {
+    if let Some(on_event) = self.on_event.as_mut() {
+      on_event(event);
+    }
+  }
+
+  fn emit(&mut self, mut motion: MotionSample, dt_sec: f64) {
+    motion.sensor = self.sensor;
+
+    if let Some(jerk) = self.jerk.as_mut() {
+      motion.jerk = Some(jerk.update(motion.accel, dt_sec));
+    }
+
+    if let Some(rms) = self.rms.as_mut() {
+      motion.rms = Some(rms.update(motion.accel));
+    }
+
+    self.queue.push_drop_old(&motion);
+  }
+}
+
+impl RmsAccumulator {
+  #[must_use]
+  pub fn new(window_samples: usize) -> Self {
+    Self {
+      window: AllocRingBuffer::new(window_samples.max(1)),
+      sum_sq: 0.0,
+      since_resum: 0,
+    }
+  }
+
+  fn update(&mut self, accel: [f64; 3]) -> f64 {
+    let sq = accel.iter().map(|v| v * v).sum::<f64>();
+
+    self.sum_sq += sq;
+
+    if let Some(evicted) = self.window.enqueue(sq) {
+      self.sum_sq -= evicted;
+      self.since_resum += 1;
+    }
+
+    // Re-sum once per window so the running total cannot drift.
+    if self.since_resum >= self.window.capacity() {
+      self.sum_sq = self.window.iter().sum();
+      self.since_resum = 0;
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    let len = self.window.len() as f64;
+
+    (self.sum_sq.max(0.0) / len).sqrt()
+  }
+}
+
+impl JerkDifferentiator {
+  #[must_use]
+  pub fn new(cutoff_hz: Option<f64>) -> Self {
+    Self {
+      cutoff_hz: cutoff_hz.filter(|hz| *hz > 0.0),
+      prev: None,
+      jerk: [0.0; 3],
+    }
+  }
+
+  fn update(&mut self, accel: [f64; 3], dt_sec: f64) -> [f64; 3] {
+    let Some(prev) = self.prev.replace(accel) else {
+      return self.jerk;
+    };
+
+    if dt_sec <= 0.0 {
+      return self.jerk;
+    }
+
+    // Differentiation amplifies sensor noise, so optionally smooth with a one-pole lowpass.
+    let alpha = self
+      .cutoff_hz
+      .map_or(1.0, |hz| dt_sec / (1.0 / (TAU * hz) + dt_sec));
+
+    for ((j, a), p) in self.jerk.iter_mut().zip(accel).zip(prev) {
+      *j += alpha * ((a - p) / dt_sec - *j);
+    }
+
+    self.jerk
+  }
+}
+
+impl IdleDownclock {
+  #[must_use]
+  pub fn new(threshold_mps2: f64, after_secs: f64) -> Self {
+    Self {
+      threshold_mps2,
+      after_secs,
+      still_secs: 0.0,
+      last_accel: None,
+      idle: false,
+      skipped: 0,
+      pending_dt: 0.0,
+    }
+  }
+
+  // Returns the merged dt and how many samples it covers.
+  fn admit(&mut self, sample: &ImuSample) -> Option<(f64, u32)> {
+    let accel = sample.accel_mps2;
+    let moved = self.last_accel.is_some_and(|last| {
+      let d = [accel[0] - last[0], accel[1] - last[1], accel[2] - last[2]];
+
+      (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt() > self.threshold_mps2
+    });
+
+    self.last_accel = Some(accel);
+
+    if moved {
+      self.still_secs = 0.0;
+
+      self.idle = false;
+    } else {
+      self.still_secs += sample.dt_sec;
+
+      if self.still_secs >= self.after_secs {
+        self.idle = true;
+      }
+    }
+
+    let dt_sec = self.pending_dt + sample.dt_sec;
+
+    if self.idle && self.skipped + 1 < IDLE_DOWNCLOCK_FACTOR {
+      self.skipped += 1;
+      self.pending_dt = dt_sec;
+
+      return None;
+    }
+
+    let periods = self.skipped + 1;
+
+    self.skipped = 0;
+    self.pending_dt = 0.0;
+
+    Some((dt_sec, periods))
+  }
+}
+
+impl SharedFusionState {
+  fn record(&self, snapshot: FusionSnapshot) {
+    *self.latest.lock().unwrap() = snapshot;
+    self.sample_count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> FusionSnapshot {
+    *self.latest.lock().unwrap()
+  }
+
+  pub fn last_accel_linear(&self) -> [f64; 3] {
+    self.latest.lock().unwrap().accel_linear
+  }
+
+  pub fn last_orientation(&self) -> [f64; 4] {
+    self.latest.lock().unwrap().orientation_wxyz
+  }
+
+  pub fn sample_count(&self) -> u64 {
+    self.sample_count.load(Ordering::Relaxed)
+  }
+}
+
+#[must_use]
+pub fn sample_clock_ms(monotonic: bool) -> f64 {
+  // Config::validate has already read the boot clock once, so the wall clock
+  // is only a last resort here.
+  if monotonic {
+    boottime_ms().unwrap_or_else(|_| now_ms())
+  } else {
+    now_ms()
+  }
+}
+
+#[must_use]
+pub fn now_ms() -> f64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_millis_f64()
+}
+
+impl PipelineInput {
+  pub fn push(&self, sample: ImuSample) {
+    self.pipeline.lock().unwrap().step(sample);
+  }
+
+  pub fn set_fusion(&self, fusion: Option<FusionEngine>) {
+    self.pipeline.lock().unwrap().set_fusion(fusion);
+  }
+
+  pub fn set_rms(&self, rms: RmsAccumulator) {
+    self.pipeline.lock().unwrap().set_rms(rms);
+  }
+
+  pub fn reset_fusion(&self) {
+    self.pipeline.lock().unwrap().reset_fusion();
+  }
+
+  #[must_use]
+  pub fn shared_state(&self) -> Arc<SharedFusionState> {
+    Arc::clone(&self.shared)
+  }
+
+  pub fn push_raw(&self, accel_mps2: [f64; 3], gyro: Option<[f64; 3]>, dt_sec: f64) {
+    self.push(ImuSample {
+      accel_mps2,
+      gyro,
+      dt_sec,
+      ..ImuSample::default()
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{IdleDownclock, MotionSample, Pipeline, PipelineEvent, SampleQueue, SampleQueueMode};
+  use crate::sensor::ImuSample;
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+
+  fn at(t_ms: f64) -> MotionSample {
+    MotionSample {
+      t_ms,
+      ..MotionSample::default()
+    }
+  }
+
+  fn times(samples: &[MotionSample]) -> Vec<f64> {
+    samples.iter().map(|sample| sample.t_ms).collect()
+  }
+
+  #[test]
+  fn timestamp_queue_drains_in_time_order_after_the_hold_back() {
+    let queue = SampleQueue::new(8, SampleQueueMode::Timestamp)
+      .with_reorder_latency(Duration::from_millis(20), 0.0);
+
+    for t_ms in [10.0, 30.0, 20.0, 25.0] {
+      queue.push_drop_old(&at(t_ms));
+    }
+
+    // Newest is 30, so only samples at or before 10 are released.
+    assert_eq!(times(&queue.drain_available()), [10.0]);
+
+    queue.push_drop_old(&at(45.0));
+
+    assert_eq!(times(&queue.drain_available()), [20.0, 25.0]);
+    assert_eq!(queue.metrics().current_len, 2);
+  }
+
+  #[test]
+  fn timestamp_queue_keeps_a_full_reorder_window_above_its_capacity() {
+    // 200 samples/s over 20 ms holds back 4 samples on top of a capacity of 2.
+    let queue = SampleQueue::new(2, SampleQueueMode::Timestamp)
+      .with_reorder_latency(Duration::from_millis(20), 200.0);
+
+    for t_ms in [5.0, 0.0, 15.0, 10.0, 20.0, 40.0] {
+      queue.push_drop_old(&at(t_ms));
+    }
+
+    assert_eq!(queue.metrics().total_dropped, 0);
+    assert_eq!(
+      times(&queue.drain_available()),
+      [0.0, 5.0, 10.0, 15.0, 20.0]
+    );
+  }
+
+  #[test]
+  fn stalled_timestamp_queue_releases_held_samples() {
+    let latency = Duration::from_millis(20);
+    let queue = SampleQueue::new(4, SampleQueueMode::Timestamp).with_reorder_latency(latency, 0.0);
+
+    queue.push_drop_old(&at(20.0));
+    queue.push_drop_old(&at(10.0));
+
+    assert!(queue.drain_available().is_empty());
+    assert!(queue.drain_stalled(Instant::now()).is_empty());
+
+    let deadline = queue.stall_deadline().unwrap();
+
+    assert_eq!(times(&queue.drain_stalled(deadline)), [10.0, 20.0]);
+    assert!(queue.stall_deadline().is_none());
+  }
+
+  #[test]
+  fn full_timestamp_queue_evicts_the_oldest_sample() {
+    let queue = SampleQueue::new(2, SampleQueueMode::Timestamp);
+
+    for t_ms in [10.0, 20.0, 30.0] {
+      queue.push_drop_old(&at(t_ms));
+    }
+
+    assert_eq!(queue.metrics().total_dropped, 1);
+    assert_eq!(times(&queue.drain_available()), [20.0, 30.0]);
+  }
+
+  #[test]
+  fn full_timestamp_queue_drops_an_incoming_sample_older_than_all_queued() {
+    let queue = SampleQueue::new(2, SampleQueueMode::Timestamp);
+
+    for t_ms in [20.0, 30.0, 10.0] {
+      queue.push_drop_old(&at(t_ms));
+    }
+
+    assert_eq!(queue.metrics().total_dropped, 1);
+    assert_eq!(times(&queue.drain_available()), [20.0, 30.0]);
+  }
+
+  #[test]
+  fn fifo_queue_ignores_the_reorder_latency() {
+    let queue = SampleQueue::new(4, SampleQueueMode::Fifo)
+      .with_reorder_latency(Duration::from_millis(20), 100.0);
+
+    for t_ms in [30.0, 10.0] {
+      queue.push_drop_old(&at(t_ms));
+    }
+
+    assert_eq!(times(&queue.drain_available()), [30.0, 10.0]);
+  }
+
+  #[test]
+  fn idle_transitions_reach_the_event_handler() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    let queue = Arc::new(SampleQueue::new(64, SampleQueueMode::Fifo));
+    let mut pipeline = Pipeline::new(None, queue)
+      .with_idle_downclock(IdleDownclock::new(0.5, 1.0))
+      .with_event_handler(move |event| sink.lock().unwrap().push(event));
+    let still = ImuSample {
+      accel_mps2: [0.0, 0.0, 9.8],
+      dt_sec: 0.25,
+      ..ImuSample::default()
+    };
+
+    for _ in 0..8 {
+      pipeline.step(still);
+    }
+
+    pipeline.step(ImuSample {
+      accel_mps2: [3.0, 0.0, 9.8],
+      ..still
+    });
+
+    assert_eq!(
+      *events.lock().unwrap(),
+      [
+        PipelineEvent::IdleEntered { still_secs: 1.0 },
+        PipelineEvent::IdleExited
+      ]
+    );
+  }
+}
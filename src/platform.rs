@@ -0,0 +1,194 @@
+use crate::error::{Error, Result};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use nix::sys::utsname::uname;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CpuList(pub Vec<usize>);
+
+const MIN_KERNEL: (u32, u32, u32) = (5, 10, 0);
+const MIN_HRTIMER_KERNEL: (u32, u32, u32) = (5, 0, 0);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn kernel_version() -> Result<(u32, u32, u32)> {
+  let uts = uname().map_err(|err| Error::invalid_state_with("uname failed", err))?;
+  let release = uts.release().to_string_lossy();
+
+  parse_kernel_release(&release)
+    .ok_or_else(|| Error::invalid_state(format!("unrecognized kernel release {release:?}")))
+}
+
+pub fn check_kernel_version() {
+  if !cfg!(target_os = "linux") {
+    eprintln!("kernel version check only applies to linux, skipping");
+
+    return;
+  }
+
+  let version = match kernel_version() {
+    Ok(version) => version,
+    Err(err) => {
+      eprintln!("kernel version check failed: {err}");
+
+      return;
+    }
+  };
+  let (major, minor, patch) = version;
+
+  if version < MIN_HRTIMER_KERNEL {
+    eprintln!(
+      "warning: kernel {major}.{minor}.{patch} may lack iio_trig_hrtimer support (needs >= {}.{})",
+      MIN_HRTIMER_KERNEL.0, MIN_HRTIMER_KERNEL.1
+    );
+  }
+
+  if version < MIN_KERNEL {
+    eprintln!(
+      "warning: kernel {major}.{minor}.{patch} is older than {}.{}, iio buffer/trigger handling may misbehave",
+      MIN_KERNEL.0, MIN_KERNEL.1
+    );
+  }
+}
+
+fn parse_kernel_release(release: &str) -> Option<(u32, u32, u32)> {
+  let mut parts = release.split('.').map(|part| {
+    let digits = part
+      .find(|c: char| !c.is_ascii_digit())
+      .map_or(part, |end| &part[..end]);
+
+    digits.parse::<u32>().ok()
+  });
+
+  let major = parts.next()??;
+  let minor = parts.next().flatten().unwrap_or(0);
+  let patch = parts.next().flatten().unwrap_or(0);
+
+  Some((major, minor, patch))
+}
+
+impl CpuList {
+  pub fn parse(spec: &str) -> Result<Self> {
+    let mut cpus = Vec::new();
+
+    for part in spec
+      .split(',')
+      .map(str::trim)
+      .filter(|part| !part.is_empty())
+    {
+      let parse = |value: &str| {
+        value
+          .trim()
+          .parse::<usize>()
+          .map_err(|err| Error::invalid_state_with(format!("invalid cpu {value:?}"), err))
+      };
+
+      match part.split_once('-') {
+        Some((start, end)) => {
+          let (start, end) = (parse(start)?, parse(end)?);
+
+          if start > end {
+            return Err(Error::invalid_state(format!("invalid cpu range {part:?}")));
+          }
+
+          cpus.extend(start..=end);
+        }
+
+        None => cpus.push(parse(part)?),
+      }
+    }
+
+    if cpus.is_empty() {
+      return Err(Error::invalid_state("cpu list is empty"));
+    }
+
+    Ok(Self(cpus))
+  }
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_thread_realtime(priority: u8) -> Result<()> {
+  let policy = if priority == 0 {
+    libc::SCHED_OTHER
+  } else {
+    libc::SCHED_FIFO
+  };
+  let param = libc::sched_param {
+    sched_priority: i32::from(priority),
+  };
+  // SAFETY: pid 0 targets the calling thread and `param` outlives the call.
+  let ret = unsafe { libc::sched_setscheduler(0, policy, &raw const param) };
+
+  if ret != 0 {
+    return Err(Error::invalid_state_with(
+      format!("failed to set SCHED_FIFO priority {priority}"),
+      std::io::Error::last_os_error(),
+    ));
+  }
+
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_thread_realtime(_priority: u8) -> Result<()> {
+  Err(Error::invalid_state(
+    "realtime thread priority is only supported on linux",
+  ))
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_thread_affinity(cpus: &CpuList) -> Result<()> {
+  use nix::sched::{CpuSet, sched_setaffinity};
+  use nix::unistd::Pid;
+
+  let mut set = CpuSet::new();
+
+  for &cpu in &cpus.0 {
+    set
+      .set(cpu)
+      .map_err(|err| Error::invalid_state_with(format!("invalid cpu {cpu}"), err))?;
+  }
+
+  sched_setaffinity(Pid::from_raw(0), &set)
+    .map_err(|err| Error::invalid_state_with("failed to set cpu affinity", err))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_thread_affinity(_cpus: &CpuList) -> Result<()> {
+  Err(Error::invalid_state(
+    "cpu affinity is only supported on linux",
+  ))
+}
+
+// Without SA_RESTART a read blocked on the signalled thread returns EINTR, and
+// any other read returns with its next sample; either way the read loop sees
+// the flag and returns, dropping the poller to restore the device.
+// SA_RESETHAND lets a second signal kill the process if that stalls.
+pub fn install_shutdown_handler() -> Result<()> {
+  let action = SigAction::new(
+    SigHandler::Handler(handle_shutdown_signal),
+    SaFlags::SA_RESETHAND,
+    SigSet::empty(),
+  );
+
+  for signal in [Signal::SIGINT, Signal::SIGTERM] {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe { sigaction(signal, &action) }.map_err(|err| {
+      Error::invalid_state_with(format!("failed to install a {signal} handler"), err)
+    })?;
+  }
+
+  Ok(())
+}
+
+extern "C" fn handle_shutdown_signal(_signal: libc::c_int) {
+  request_shutdown();
+}
+
+pub fn request_shutdown() {
+  SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+pub fn shutdown_requested() -> bool {
+  SHUTDOWN.load(Ordering::Relaxed)
+}
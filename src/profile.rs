@@ -0,0 +1,120 @@
+use crate::metrics::Metrics;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+const DT_BUCKETS_MS: [f64; 8] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, f64::INFINITY];
+const RATE_EMA_ALPHA: f64 = 0.05;
+
+pub struct Profiler {
+  started: Instant,
+  duration: Duration,
+  dt_counts: [u64; DT_BUCKETS_MS.len()],
+  rate_ema_hz: Option<f64>,
+  fusion_time: Duration,
+  fusion_max: Duration,
+  ws_rtt_max_us: u64,
+  samples: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileReport {
+  pub duration_secs: f64,
+  pub samples: u64,
+  pub dt_histogram_ms: Vec<DtBucket>,
+  pub rate_ema_hz: Option<f64>,
+  pub fusion_mean_us: f64,
+  pub fusion_max_us: f64,
+  pub batches: u64,
+  pub batch_mean_bytes: f64,
+  pub batches_dropped: u64,
+  pub ws_sent: u64,
+  pub ws_errors: u64,
+  pub ws_rtt_us: u64,
+  pub ws_rtt_max_us: u64,
+  pub ws_rtt_note: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DtBucket {
+  pub le: Option<f64>,
+  pub count: u64,
+}
+
+impl Profiler {
+  pub fn new(duration: Duration) -> Self {
+    Self {
+      started: Instant::now(),
+      duration,
+      dt_counts: [0; DT_BUCKETS_MS.len()],
+      rate_ema_hz: None,
+      fusion_time: Duration::ZERO,
+      fusion_max: Duration::ZERO,
+      ws_rtt_max_us: 0,
+      samples: 0,
+    }
+  }
+
+  // ws_rtt_us is the latest keepalive round trip, sampled here to track its peak.
+  pub fn record(&mut self, dt_sec: f64, fusion_time: Duration, ws_rtt_us: u64) {
+    let dt_ms = dt_sec * 1000.0;
+
+    if let Some(bucket) = DT_BUCKETS_MS.iter().position(|le| dt_ms <= *le) {
+      self.dt_counts[bucket] += 1;
+    }
+
+    if dt_sec > 0.0 {
+      let rate = 1.0 / dt_sec;
+
+      self.rate_ema_hz = Some(match self.rate_ema_hz {
+        Some(ema) => ema + RATE_EMA_ALPHA * (rate - ema),
+        None => rate,
+      });
+    }
+
+    self.fusion_time += fusion_time;
+    self.fusion_max = self.fusion_max.max(fusion_time);
+    self.ws_rtt_max_us = self.ws_rtt_max_us.max(ws_rtt_us);
+    self.samples += 1;
+  }
+
+  pub fn is_done(&self) -> bool {
+    self.started.elapsed() >= self.duration
+  }
+
+  pub fn report(&self, metrics: &Metrics) -> ProfileReport {
+    let batches = metrics.batches.load(Ordering::Relaxed);
+    let batch_bytes = metrics.batch_bytes.load(Ordering::Relaxed);
+    let samples = u32::try_from(self.samples).unwrap_or(u32::MAX).max(1);
+    let batch_count = u32::try_from(batches).unwrap_or(u32::MAX).max(1);
+    let ws_rtt_us = metrics.ws_rtt_us.load(Ordering::Relaxed);
+
+    ProfileReport {
+      duration_secs: self.started.elapsed().as_secs_f64(),
+      samples: self.samples,
+      dt_histogram_ms: DT_BUCKETS_MS
+        .iter()
+        .zip(self.dt_counts)
+        .map(|(le, count)| DtBucket {
+          le: le.is_finite().then_some(*le),
+          count,
+        })
+        .collect(),
+      rate_ema_hz: self.rate_ema_hz,
+      fusion_mean_us: (self.fusion_time / samples).as_secs_f64() * 1e6,
+      fusion_max_us: self.fusion_max.as_secs_f64() * 1e6,
+      batches,
+      batch_mean_bytes: f64::from(u32::try_from(batch_bytes).unwrap_or(u32::MAX))
+        / f64::from(batch_count),
+      batches_dropped: metrics.batches_dropped.load(Ordering::Relaxed),
+      ws_sent: metrics.ws_sent.load(Ordering::Relaxed),
+      ws_errors: metrics.ws_errors.load(Ordering::Relaxed),
+      ws_rtt_us,
+      ws_rtt_max_us: self.ws_rtt_max_us.max(ws_rtt_us),
+      ws_rtt_note: (ws_rtt_us == 0)
+        .then_some("no pong measured; keepalive pings only run with --adaptive-batch"),
+    }
+  }
+}
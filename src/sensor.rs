@@ -0,0 +1,88 @@
+use crate::error::Result;
+use clap::ValueEnum;
+use std::f64::consts::TAU;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Backend {
+  Iio,
+  Synthetic,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImuSample {
+  pub accel_mps2: [f64; 3],
+  pub gyro: Option<[f64; 3]>,
+  pub dt_sec: f64,
+  pub mag_ut: Option<[f64; 3]>,
+  pub temp_c: Option<f64>,
+  pub accel_raw: Option<[i64; 3]>,
+  pub gyro_raw: Option<[i64; 3]>,
+  pub accel_saturated: bool,
+}
+
+pub trait SensorPoller {
+  /// # Errors
+  ///
+  /// Returns the backend's error when no sample could be read.
+  fn read_sample(&mut self) -> Result<ImuSample>;
+
+  fn describe(&self) -> String;
+
+  /// # Errors
+  ///
+  /// Returns an error when the backend cannot be reopened at `rate_hz`.
+  fn check_rate(&self, _rate_hz: u32) -> Result<()> {
+    Ok(())
+  }
+
+  fn device_id(&self) -> Option<String> {
+    None
+  }
+}
+
+pub struct SyntheticImuPoller {
+  period: Duration,
+  next_at: Instant,
+  phase: f64,
+}
+
+impl SyntheticImuPoller {
+  #[must_use]
+  pub fn new(rate_hz: u32) -> Self {
+    let period = Duration::from_nanos((1_000_000_000_u64 / u64::from(rate_hz)).max(1));
+
+    Self {
+      period,
+      next_at: Instant::now(),
+      phase: 0.0,
+    }
+  }
+}
+
+impl SensorPoller for SyntheticImuPoller {
+  fn describe(&self) -> String {
+    format!("synthetic period={:?}", self.period)
+  }
+
+  fn read_sample(&mut self) -> Result<ImuSample> {
+    if let Some(sleep) = self.next_at.checked_duration_since(Instant::now()) {
+      thread::sleep(sleep);
+    }
+
+    let dt_sec = self.period.as_secs_f64();
+
+    self.next_at += self.period;
+    self.phase = (self.phase + TAU * dt_sec) % TAU;
+
+    let wobble = 0.05 * self.phase.sin();
+
+    Ok(ImuSample {
+      accel_mps2: [wobble, 0.5 * wobble, 9.806_65 + 0.1 * wobble],
+      gyro: Some([0.0; 3]),
+      dt_sec,
+      ..ImuSample::default()
+    })
+  }
+}
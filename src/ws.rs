@@ -1,48 +1,285 @@
 use crate::error::{Error, Result};
+use serde::Deserialize;
 use std::io;
 use std::net::TcpStream;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::protocol::frame::Frame;
+use tungstenite::protocol::frame::coding::{Data, OpCode};
 use tungstenite::stream::MaybeTlsStream;
 use tungstenite::{Message, WebSocket};
 use url::Url;
 
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 << 20;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(10);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REDIRECTS: u8 = 3;
+const MAX_SERVER_COMMAND_BYTES: usize = 4096;
+
 pub struct WsClient {
   url: Url,
   socket: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+  max_rejections: u32,
+  rejections: u32,
+  max_message_size: usize,
+  max_frame_bytes: usize,
+  warned_message_size: bool,
+  needs_flush: bool,
+  read_timeout: Duration,
+  write_timeout: Duration,
+  commands: Option<mpsc::SyncSender<ServerCommand>>,
+  keepalive: Option<Keepalive>,
+}
+
+struct Keepalive {
+  interval: Duration,
+  last_ping: Option<Instant>,
+  outstanding: Option<(u64, Instant)>,
+  seq: u64,
+  rtt_us: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ServerCommand {
+  SetRate { rate: u32 },
+  ResetFusion,
+}
+
+pub struct FailoverWsClient {
+  clients: Vec<WsClient>,
+  current: usize,
+  failures: u32,
+  failover_after: u32,
+  recover_after: Duration,
+  switched_at: Option<Instant>,
+}
+
+pub struct SendRateLimiter {
+  rate_hz: f64,
+  tokens: f64,
+  last_refill: Instant,
 }
 
 impl WsClient {
   pub fn new(url: Url) -> Self {
-    Self { url, socket: None }
+    Self {
+      url,
+      socket: None,
+      max_rejections: 0,
+      rejections: 0,
+      max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+      max_frame_bytes: 0,
+      warned_message_size: false,
+      needs_flush: false,
+      read_timeout: DEFAULT_READ_TIMEOUT,
+      write_timeout: DEFAULT_WRITE_TIMEOUT,
+      commands: None,
+      keepalive: None,
+    }
+  }
+
+  pub fn with_timeouts(mut self, read_timeout: Duration, write_timeout: Duration) -> Self {
+    self.read_timeout = read_timeout;
+    self.write_timeout = write_timeout;
+    self
+  }
+
+  pub fn with_commands(mut self, commands: mpsc::SyncSender<ServerCommand>) -> Self {
+    self.commands = Some(commands);
+    self
+  }
+
+  // The latest round trip lands in rtt_us in microseconds; 0 means none has
+  // completed yet.
+  pub fn with_keepalive(mut self, interval: Duration, rtt_us: Arc<AtomicU64>) -> Self {
+    self.keepalive = Some(Keepalive {
+      interval,
+      last_ping: None,
+      outstanding: None,
+      seq: 0,
+      rtt_us,
+    });
+    self
+  }
+
+  pub fn with_max_rejections(mut self, max_rejections: u32) -> Self {
+    self.max_rejections = max_rejections;
+    self
+  }
+
+  pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+    self.max_message_size = max_message_size;
+    self
+  }
+
+  pub fn with_max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+    self.max_frame_bytes = max_frame_bytes;
+    self
+  }
+
+  pub fn url(&self) -> &Url {
+    &self.url
   }
 
   pub fn is_connected(&self) -> bool {
     self.socket.is_some()
   }
 
+  pub fn is_circuit_open(&self) -> bool {
+    self.max_rejections > 0 && self.rejections >= self.max_rejections
+  }
+
+  pub fn half_open_circuit(&mut self) {
+    self.rejections = self.max_rejections.saturating_sub(1);
+  }
+
   pub fn poll_connect(&mut self) -> Result<()> {
     self.maybe_connect()
   }
 
   pub fn send_text(&mut self, text: String) -> Result<bool> {
+    if self.max_frame_bytes > 0 && text.len() > self.max_frame_bytes {
+      return self.send_text_fragmented(text, self.max_frame_bytes);
+    }
+
+    if !self.check_message_size(text.len()) {
+      return Ok(false);
+    }
+
     self.maybe_connect()?;
 
     let Some(mut socket) = self.socket.take() else {
       return Ok(false);
     };
+    let result = socket.write(Message::Text(text));
 
-    match socket.send(Message::Text(text)) {
-      Ok(()) => {
-        self.socket = Some(socket);
+    self.finish_write(socket, result)?;
+
+    Ok(true)
+  }
+
+  pub fn send_text_fragmented(&mut self, text: String, max_frame_bytes: usize) -> Result<bool> {
+    if !self.check_message_size(text.len()) {
+      return Ok(false);
+    }
+
+    self.maybe_connect()?;
+
+    let Some(mut socket) = self.socket.take() else {
+      return Ok(false);
+    };
+    let payload = text.into_bytes();
+    let chunk_count = payload.len().div_ceil(max_frame_bytes.max(1));
+
+    for (i, chunk) in payload.chunks(max_frame_bytes.max(1)).enumerate() {
+      let opcode = if i == 0 {
+        OpCode::Data(Data::Text)
+      } else {
+        OpCode::Data(Data::Continue)
+      };
+      let frame = Frame::message(chunk.to_vec(), opcode, i + 1 == chunk_count);
 
-        Ok(true)
+      match socket.write(Message::Frame(frame)) {
+        Err(err) if !is_transient_io(&err) => return Err(Error::from(err)),
+        _ => {}
       }
+    }
 
-      Err(err) => Err(Error::from(err)),
+    self.finish_write(socket, Ok(()))?;
+
+    Ok(true)
+  }
+
+  // A WouldBlock from write or flush leaves the frame queued inside tungstenite
+  // (possibly as a partially written TLS record), so it is retried rather than
+  // treated as a failed send.
+  fn finish_write(
+    &mut self,
+    mut socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    result: tungstenite::Result<()>,
+  ) -> Result<()> {
+    let flushed = match result {
+      Ok(()) => socket.flush(),
+      Err(err) => Err(err),
+    };
+
+    match flushed {
+      Ok(()) => self.needs_flush = false,
+      Err(err) if is_transient_io(&err) => self.needs_flush = true,
+      Err(err) => return Err(Error::from(err)),
     }
+
+    self.socket = Some(socket);
+
+    Ok(())
+  }
+
+  fn poll_flush(&mut self) -> Result<()> {
+    if !self.needs_flush {
+      return Ok(());
+    }
+
+    let Some(socket) = self.socket.take() else {
+      self.needs_flush = false;
+
+      return Ok(());
+    };
+
+    self.finish_write(socket, Ok(()))
+  }
+
+  // Sends a ping once per keepalive interval; a ping that is never answered is
+  // replaced after another interval rather than blocking later measurements.
+  pub fn poll_keepalive(&mut self, now: Instant) -> Result<()> {
+    let Some(keepalive) = self.keepalive.as_mut() else {
+      return Ok(());
+    };
+
+    if keepalive
+      .last_ping
+      .is_some_and(|sent| now.duration_since(sent) < keepalive.interval)
+    {
+      return Ok(());
+    }
+
+    let Some(mut socket) = self.socket.take() else {
+      return Ok(());
+    };
+
+    keepalive.seq = keepalive.seq.wrapping_add(1);
+    keepalive.last_ping = Some(now);
+    keepalive.outstanding = Some((keepalive.seq, now));
+
+    let result = socket.write(Message::Ping(keepalive.seq.to_be_bytes().to_vec()));
+
+    self.finish_write(socket, result)
+  }
+
+  fn record_pong(&mut self, payload: &[u8]) {
+    let Some(keepalive) = self.keepalive.as_mut() else {
+      return;
+    };
+    let Some((seq, sent)) = keepalive.outstanding else {
+      return;
+    };
+
+    if payload != seq.to_be_bytes() {
+      return;
+    }
+
+    let rtt_us = u64::try_from(sent.elapsed().as_micros()).unwrap_or(u64::MAX);
+
+    keepalive.rtt_us.store(rtt_us.max(1), Ordering::Relaxed);
+    keepalive.outstanding = None;
   }
 
   pub fn poll_incoming(&mut self) -> Result<()> {
+    self.poll_flush()?;
+
     let Some(mut socket) = self.socket.take() else {
       return Ok(());
     };
@@ -54,21 +291,20 @@ impl WsClient {
             socket.send(Message::Pong(payload)).map_err(Error::from)?;
           }
 
+          Message::Pong(payload) => self.record_pong(&payload),
+
           Message::Close(frame) => {
             let _ = socket.close(frame);
 
             return Ok(());
           }
 
+          Message::Text(text) => self.dispatch_command(&text),
+
           _ => {}
         },
 
-        Err(tungstenite::Error::Io(err))
-          if matches!(
-            err.kind(),
-            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
-          ) =>
-        {
+        Err(err) if is_transient_io(&err) => {
           self.socket = Some(socket);
 
           return Ok(());
@@ -85,35 +321,285 @@ impl WsClient {
     }
   }
 
+  fn dispatch_command(&self, text: &str) {
+    let Some(commands) = self.commands.as_ref() else {
+      return;
+    };
+
+    match ServerCommand::parse(text.as_bytes()) {
+      Ok(command) => {
+        if let Err(mpsc::TrySendError::Full(command)) = commands.try_send(command) {
+          eprintln!("server command queue full, dropping {command:?}");
+        }
+      }
+      Err(err) => eprintln!("ignoring unrecognized server message: {err}"),
+    }
+  }
+
   fn maybe_connect(&mut self) -> Result<()> {
     if self.socket.is_some() {
       return Ok(());
     }
 
-    match tungstenite::connect(self.url.clone()) {
+    match tungstenite::client::connect_with_config(
+      self.url.clone(),
+      Some(self.socket_config()),
+      MAX_REDIRECTS,
+    ) {
       Ok((socket, _response)) => {
         let mut socket = socket;
 
-        Self::configure_socket(&mut socket)?;
+        self.configure_socket(&mut socket)?;
         self.socket = Some(socket);
+        self.rejections = 0;
+        self.needs_flush = false;
 
         Ok(())
       }
 
+      Err(tungstenite::Error::Http(response)) if response.status().is_client_error() => {
+        self.rejections = self.rejections.saturating_add(1);
+
+        Err(Error::WsRejected(response.status().as_u16()))
+      }
+
       Err(err) => Err(Error::from(err)),
     }
   }
 
-  fn configure_socket(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<()> {
-    let stream = socket.get_mut();
-    let timeout = Duration::from_millis(10);
-    let result = match stream {
-      MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(timeout)),
-      MaybeTlsStream::Rustls(stream) => stream.get_mut().set_read_timeout(Some(timeout)),
-      _ => Ok(()),
+  fn socket_config(&self) -> WebSocketConfig {
+    WebSocketConfig {
+      max_message_size: Some(self.max_message_size),
+      max_frame_size: Some(self.max_message_size),
+      ..WebSocketConfig::default()
+    }
+  }
+
+  fn check_message_size(&mut self, len: usize) -> bool {
+    if len > self.max_message_size {
+      eprintln!(
+        "warning: dropping {len} byte batch that exceeds the websocket message limit of {} bytes; lower --batch",
+        self.max_message_size
+      );
+
+      return false;
+    }
+
+    if !self.warned_message_size && len >= self.max_message_size / 4 * 3 {
+      self.warned_message_size = true;
+      eprintln!(
+        "warning: {len} byte batch is close to the websocket message limit of {} bytes; consider lowering --batch",
+        self.max_message_size
+      );
+    }
+
+    true
+  }
+
+  // Write timeouts surface as TimedOut and are retried through `needs_flush`, so a
+  // stalled peer cannot wedge ws_loop on either direction.
+  fn configure_socket(&self, socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<()> {
+    let stream = match socket.get_mut() {
+      MaybeTlsStream::Plain(stream) => stream,
+      MaybeTlsStream::Rustls(stream) => stream.get_mut(),
+      _ => return Ok(()),
     };
+    let result = stream
+      .set_read_timeout(Some(self.read_timeout))
+      .and_then(|()| stream.set_write_timeout(Some(self.write_timeout)));
+
+    result.map_err(|err| Error::invalid_state_with("websocket configure has been fucked", err))
+  }
+}
+
+impl FailoverWsClient {
+  pub fn new(
+    primary: WsClient,
+    failover: Option<WsClient>,
+    failover_after: u32,
+    recover_after: Duration,
+  ) -> Self {
+    let mut clients = vec![primary];
+
+    clients.extend(failover);
 
+    Self {
+      clients,
+      current: 0,
+      failures: 0,
+      failover_after: failover_after.max(1),
+      recover_after,
+      switched_at: None,
+    }
+  }
+
+  pub fn is_connected(&self) -> bool {
+    self.clients[self.current].is_connected()
+  }
+
+  pub fn is_circuit_open(&self) -> bool {
+    self.clients[self.current].is_circuit_open()
+  }
+
+  pub fn half_open_circuit(&mut self) {
+    self.clients[self.current].half_open_circuit();
+  }
+
+  pub fn poll_connect(&mut self) -> Result<()> {
+    self.maybe_recover_primary();
+
+    let result = self.clients[self.current].poll_connect();
+
+    self.record(result.is_ok());
     result
-      .map_err(|err| Error::invalid_state(format!("websocket configure has been fucked: {err}")))
+  }
+
+  pub fn send_text(&mut self, text: String) -> Result<bool> {
+    self.maybe_recover_primary();
+
+    let result = self.clients[self.current].send_text(text);
+
+    self.record(result.is_ok());
+    result
+  }
+
+  pub fn poll_keepalive(&mut self, now: Instant) -> Result<()> {
+    self.clients[self.current].poll_keepalive(now)
+  }
+
+  pub fn poll_incoming(&mut self) -> Result<()> {
+    self.clients[self.current].poll_incoming()
+  }
+
+  fn record(&mut self, ok: bool) {
+    if ok {
+      self.failures = 0;
+
+      return;
+    }
+
+    self.failures = self.failures.saturating_add(1);
+
+    if self.current == 0 && self.clients.len() > 1 && self.failures >= self.failover_after {
+      self.switch_to(1);
+    }
+  }
+
+  fn maybe_recover_primary(&mut self) {
+    if self.current == 0 {
+      return;
+    }
+
+    if self
+      .switched_at
+      .is_some_and(|at| at.elapsed() >= self.recover_after)
+    {
+      self.switch_to(0);
+    }
+  }
+
+  fn switch_to(&mut self, index: usize) {
+    eprintln!(
+      "websocket failover: switching from {} to {} after {} failures",
+      self.clients[self.current].url(),
+      self.clients[index].url(),
+      self.failures,
+    );
+
+    self.current = index;
+    self.failures = 0;
+    self.switched_at = Some(Instant::now());
+  }
+}
+
+impl ServerCommand {
+  pub fn parse(bytes: &[u8]) -> Result<Self> {
+    if bytes.len() > MAX_SERVER_COMMAND_BYTES {
+      return Err(Error::invalid_state(format!(
+        "server command is {} bytes, limit is {MAX_SERVER_COMMAND_BYTES}",
+        bytes.len()
+      )));
+    }
+
+    Ok(serde_json::from_slice(bytes)?)
+  }
+}
+
+impl SendRateLimiter {
+  pub fn new(rate_hz: f64) -> Self {
+    Self {
+      rate_hz,
+      tokens: 1.0,
+      last_refill: Instant::now(),
+    }
+  }
+
+  pub fn try_consume(&mut self) -> Option<Duration> {
+    if self.rate_hz <= 0.0 {
+      return None;
+    }
+
+    let now = Instant::now();
+
+    self.tokens =
+      (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate_hz).min(1.0);
+    self.last_refill = now;
+
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+
+      return None;
+    }
+
+    Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate_hz))
+  }
+}
+
+fn is_transient_io(err: &tungstenite::Error) -> bool {
+  matches!(
+    err,
+    tungstenite::Error::Io(err) if matches!(
+      err.kind(),
+      io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{MAX_SERVER_COMMAND_BYTES, ServerCommand};
+
+  #[test]
+  fn parses_known_commands() {
+    assert_eq!(
+      ServerCommand::parse(br#"{"cmd":"set_rate","rate":200}"#).unwrap(),
+      ServerCommand::SetRate { rate: 200 }
+    );
+    assert_eq!(
+      ServerCommand::parse(br#"{"cmd":"reset_fusion"}"#).unwrap(),
+      ServerCommand::ResetFusion
+    );
+  }
+
+  #[test]
+  fn malformed_commands_are_errors() {
+    let oversized = vec![b' '; MAX_SERVER_COMMAND_BYTES + 1];
+    let inputs: &[&[u8]] = &[
+      b"",
+      b"\xff\xfe",
+      b"null",
+      b"[]",
+      b"{",
+      br#"{"cmd":"set_rate"}"#,
+      br#"{"cmd":"set_rate","rate":-1}"#,
+      br#"{"cmd":"set_rate","rate":1e99}"#,
+      br#"{"cmd":"unknown"}"#,
+      br#"{"rate":200}"#,
+      &oversized,
+    ];
+
+    for input in inputs {
+      assert!(ServerCommand::parse(input).is_err(), "{input:?}");
+    }
   }
 }
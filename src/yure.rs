@@ -1,73 +1,1122 @@
 use crate::{
   error::{Error, Result},
-  fusion::Algorithm,
+  fusion::{Algorithm, Tilt},
 };
+use clap::ValueEnum;
 use rand::seq::IndexedRandom as _;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use serde::Serialize;
+use serde::ser::{SerializeMap, Serializer};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
 use sys_info::{linux_os_release, os_release, os_type};
 
+pub const DEFAULT_USER_AGENT_TEMPLATE: &str =
+  "{app} v{version}-{algo}-{rate}hz on {os} {release} {arch}";
+// Keys the serializer writes under fixed names, which a renamed field must not take.
+const RESERVED_FIELD_NAMES: [&str; 16] = [
+  "sensor",
+  "pitch",
+  "roll",
+  "yaw",
+  "raw_x",
+  "raw_y",
+  "raw_z",
+  "gyro",
+  "gx",
+  "gy",
+  "gz",
+  "accelRaw",
+  "gyroRaw",
+  "q",
+  "rms",
+  "heartbeat",
+];
 const YURE_ID_LEN: usize = 11;
 const YURE_ID_CHARSET: &[u8; 8] = b"YUREyure";
+const CRC32_TABLE: [u32; 256] = crc32_table();
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug)]
 pub struct YureSample<'a> {
-  #[serde(rename = "yureId")]
-  pub yure_id: &'a str,
-  #[serde(rename = "userAgent")]
-  pub user_agent: &'a str,
+  pub names: &'a FieldNames,
+  pub sensor: Option<&'a str>,
+  pub axes: Axes,
   pub x: f64,
   pub y: f64,
   pub z: f64,
+  pub m: Option<f64>,
+  pub raw: Option<[f64; 3]>,
+  pub gyro: Option<[f64; 3]>,
+  // None leaves out gx/gy/gz; Some(None) writes them as null for a device without a gyro.
+  #[allow(clippy::option_option)]
+  pub gyro_axes: Option<Option<[f64; 3]>>,
+  pub tilt: Option<Tilt>,
   pub t: f64,
+  pub accel_raw: Option<[i64; 3]>,
+  pub gyro_raw: Option<[i64; 3]>,
+  pub q: Option<f64>,
+  pub rms: Option<f64>,
+  pub heartbeat: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldNames {
+  pub yure_id: String,
+  pub user_agent: String,
+  pub x: String,
+  pub y: String,
+  pub z: String,
+  pub m: String,
+  pub t: String,
+  magnitude: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BatchStrategy {
+  Size(usize),
+  Time(Duration),
+  Hybrid(usize, Duration),
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum OutputMode {
+  #[default]
+  Linear,
+  Tilt,
+  Jerk,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Axes {
+  pub x: bool,
+  pub y: bool,
+  pub z: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Units {
+  pub accel: &'static str,
+  pub jerk: &'static str,
+  pub gyro: &'static str,
+  pub tilt: &'static str,
+  pub t: &'static str,
+}
+
+// --accel-unit and --gyro-unit only describe the driver input; samples are
+// always converted to these before they reach the batcher.
+pub const SI_UNITS: Units = Units {
+  accel: "m/s^2",
+  jerk: "m/s^3",
+  gyro: "rad/s",
+  tilt: "deg",
+  t: "ms",
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchStats {
+  pub x_min: f64,
+  pub x_max: f64,
+  pub x_mean: f64,
+  pub y_min: f64,
+  pub y_max: f64,
+  pub y_mean: f64,
+  pub z_min: f64,
+  pub z_max: f64,
+  pub z_mean: f64,
+}
+
+struct CrcWriter<'w, W> {
+  inner: &'w mut W,
+  crc: &'w mut u32,
+}
+
+struct StatsAccumulator {
+  min: [f64; 3],
+  max: [f64; 3],
+  sum: [f64; 3],
+  count: u32,
+}
+
+struct LegacySample<'a> {
+  yure_id: &'a str,
+  user_agent: &'a str,
+  sample: &'a YureSample<'a>,
+}
+
+impl Serialize for YureSample<'_> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(None)?;
+
+    self.serialize_entries(&mut map)?;
+
+    map.end()
+  }
+}
+
+impl Serialize for LegacySample<'_> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    let names = self.sample.names;
+    let mut map = serializer.serialize_map(None)?;
+
+    map.serialize_entry(&names.yure_id, self.yure_id)?;
+    map.serialize_entry(&names.user_agent, self.user_agent)?;
+    self.sample.serialize_entries(&mut map)?;
+
+    map.end()
+  }
+}
+
+impl YureSample<'_> {
+  fn serialize_entries<M: SerializeMap>(&self, map: &mut M) -> std::result::Result<(), M::Error> {
+    let names = self.names;
+
+    if let Some(sensor) = self.sensor {
+      map.serialize_entry("sensor", sensor)?;
+    }
+
+    if let Some(tilt) = self.tilt {
+      map.serialize_entry("pitch", &tilt.pitch)?;
+      map.serialize_entry("roll", &tilt.roll)?;
+
+      if let Some(yaw) = tilt.yaw {
+        map.serialize_entry("yaw", &yaw)?;
+      }
+    } else if let Some(m) = self.m {
+      map.serialize_entry(&names.m, &m)?;
+    } else {
+      if self.axes.x {
+        map.serialize_entry(&names.x, &self.x)?;
+      }
+
+      if self.axes.y {
+        map.serialize_entry(&names.y, &self.y)?;
+      }
+
+      if self.axes.z {
+        map.serialize_entry(&names.z, &self.z)?;
+      }
+    }
+
+    if let Some([x, y, z]) = self.raw {
+      map.serialize_entry("raw_x", &x)?;
+      map.serialize_entry("raw_y", &y)?;
+      map.serialize_entry("raw_z", &z)?;
+    }
+
+    if let Some(gyro) = self.gyro.as_ref() {
+      map.serialize_entry("gyro", gyro)?;
+    }
+
+    if let Some(gyro) = self.gyro_axes {
+      map.serialize_entry("gx", &gyro.map(|[x, _, _]| x))?;
+      map.serialize_entry("gy", &gyro.map(|[_, y, _]| y))?;
+      map.serialize_entry("gz", &gyro.map(|[_, _, z]| z))?;
+    }
+
+    map.serialize_entry(&names.t, &self.t)?;
+
+    if let Some(raw) = self.accel_raw.as_ref() {
+      map.serialize_entry("accelRaw", raw)?;
+    }
+
+    if let Some(raw) = self.gyro_raw.as_ref() {
+      map.serialize_entry("gyroRaw", raw)?;
+    }
+
+    if let Some(q) = self.q {
+      map.serialize_entry("q", &q)?;
+    }
+
+    if let Some(rms) = self.rms {
+      map.serialize_entry("rms", &rms)?;
+    }
+
+    if self.heartbeat {
+      map.serialize_entry("heartbeat", &true)?;
+    }
+
+    Ok(())
+  }
+}
+
+impl Default for FieldNames {
+  fn default() -> Self {
+    Self {
+      yure_id: "yureId".into(),
+      user_agent: "userAgent".into(),
+      x: "x".into(),
+      y: "y".into(),
+      z: "z".into(),
+      m: "m".into(),
+      t: "t".into(),
+      magnitude: false,
+    }
+  }
+}
+
+impl Default for Axes {
+  fn default() -> Self {
+    Self {
+      x: true,
+      y: true,
+      z: true,
+    }
+  }
+}
+
+impl Axes {
+  pub fn parse(spec: &str) -> Result<Self> {
+    let mut axes = Self {
+      x: false,
+      y: false,
+      z: false,
+    };
+
+    for axis in spec.trim().chars() {
+      let slot = match axis.to_ascii_lowercase() {
+        'x' => &mut axes.x,
+        'y' => &mut axes.y,
+        'z' => &mut axes.z,
+        _ => {
+          return Err(Error::invalid_state(format!(
+            "unknown axis {axis:?}; expected a subset of xyz"
+          )));
+        }
+      };
+
+      if *slot {
+        return Err(Error::invalid_state(format!(
+          "axis {axis:?} is selected more than once"
+        )));
+      }
+
+      *slot = true;
+    }
+
+    if !(axes.x || axes.y || axes.z) {
+      return Err(Error::invalid_state("at least one axis must be selected"));
+    }
+
+    Ok(axes)
+  }
+}
+
+impl BatchStrategy {
+  pub fn parse(spec: &str) -> Result<Self> {
+    let parts: Vec<&str> = spec.trim().split(':').collect();
+    let count = |value: &str| match value.parse::<usize>() {
+      Ok(count) if count > 0 => Ok(count),
+      _ => Err(Error::invalid_state(format!(
+        "batch size {value:?} must be a positive integer"
+      ))),
+    };
+    let millis = |value: &str| match value.parse::<u64>() {
+      Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+      _ => Err(Error::invalid_state(format!(
+        "batch interval {value:?} must be a positive number of milliseconds"
+      ))),
+    };
+
+    match parts.as_slice() {
+      ["size", n] => Ok(Self::Size(count(n)?)),
+      ["time", ms] => Ok(Self::Time(millis(ms)?)),
+      ["hybrid", n, ms] => Ok(Self::Hybrid(count(n)?, millis(ms)?)),
+      _ => Err(Error::invalid_state(format!(
+        "unknown batch strategy {spec:?}; expected size:N, time:MS or hybrid:N:MS"
+      ))),
+    }
+  }
+
+  fn deadline(self, started: Option<Instant>) -> Option<Instant> {
+    match self {
+      Self::Size(_) => None,
+      Self::Time(interval) | Self::Hybrid(_, interval) => started.map(|at| at + interval),
+    }
+  }
+
+  fn is_due(self, pending: usize, started: Option<Instant>, now: Instant) -> bool {
+    let elapsed = |interval| started.is_some_and(|at| now.duration_since(at) >= interval);
+
+    match self {
+      Self::Size(size) => pending >= size,
+      Self::Time(interval) => elapsed(interval),
+      Self::Hybrid(size, interval) => pending >= size || elapsed(interval),
+    }
+  }
+}
+
+impl FieldNames {
+  pub fn parse(spec: &str) -> Result<Self> {
+    let mut names = Self::default();
+    let mut seen = Vec::new();
+
+    for pair in spec
+      .split(',')
+      .map(str::trim)
+      .filter(|pair| !pair.is_empty())
+    {
+      let Some((field, name)) = pair.split_once('=') else {
+        return Err(Error::invalid_state(format!(
+          "rename entry {pair:?} must be in the form field=name"
+        )));
+      };
+      let (field, name) = (field.trim(), name.trim());
+
+      if name.is_empty() {
+        return Err(Error::invalid_state(format!(
+          "rename entry {pair:?} has an empty name"
+        )));
+      }
+
+      if seen.contains(&field) {
+        return Err(Error::invalid_state(format!(
+          "field {field:?} is renamed more than once"
+        )));
+      }
+
+      let slot = match field {
+        "yureId" => &mut names.yure_id,
+        "userAgent" => &mut names.user_agent,
+        "x" => &mut names.x,
+        "y" => &mut names.y,
+        "z" => &mut names.z,
+        "t" => &mut names.t,
+        _ => {
+          return Err(Error::invalid_state(format!(
+            "unknown field {field:?}; expected one of yureId, userAgent, x, y, z, t"
+          )));
+        }
+      };
+
+      *slot = name.to_string();
+      seen.push(field);
+    }
+
+    names.validate()?;
+
+    Ok(names)
+  }
+
+  pub fn with_magnitude(mut self, name: &str) -> Result<Self> {
+    if name.trim().is_empty() {
+      return Err(Error::invalid_state("magnitude field name is empty"));
+    }
+
+    name.trim().clone_into(&mut self.m);
+    self.magnitude = true;
+    self.validate()?;
+
+    Ok(self)
+  }
+
+  fn validate(&self) -> Result<()> {
+    let all = [
+      &self.yure_id,
+      &self.user_agent,
+      &self.x,
+      &self.y,
+      &self.z,
+      &self.t,
+      &self.m,
+    ];
+    // "m" only appears on the wire with --output-magnitude, so it only claims
+    // its name then.
+    let all = if self.magnitude {
+      &all[..]
+    } else {
+      &all[..all.len() - 1]
+    };
+
+    for (i, name) in all.iter().enumerate() {
+      if all[..i].contains(name) {
+        return Err(Error::invalid_state(format!(
+          "output field name {name:?} is used more than once"
+        )));
+      }
+
+      if RESERVED_FIELD_NAMES.contains(&name.as_str()) {
+        return Err(Error::invalid_state(format!(
+          "output field name {name:?} is reserved for a fixed field"
+        )));
+      }
+    }
+
+    Ok(())
+  }
 }
 
 pub struct StreamBatcher<'a> {
-  batch_size: usize,
-  buf: Vec<YureSample<'a>>,
+  strategy: BatchStrategy,
+  batch_started: Option<Instant>,
+  out: Vec<u8>,
+  pending: usize,
+  samples_crc: u32,
+  yure_id: &'a str,
+  user_agent: &'a str,
+  envelope_version: u8,
+  crc: bool,
+  stats: Option<StatsAccumulator>,
+  units: Option<Units>,
+  batch_seq: u64,
+}
+
+pub struct CsvWriter<'a, W> {
+  writer: W,
+  yure_id: &'a str,
+  user_agent: &'a str,
+  header_written: bool,
+}
+
+impl Default for StatsAccumulator {
+  fn default() -> Self {
+    Self {
+      min: [f64::INFINITY; 3],
+      max: [f64::NEG_INFINITY; 3],
+      sum: [0.0; 3],
+      count: 0,
+    }
+  }
+}
+
+impl StatsAccumulator {
+  fn push(&mut self, sample: &YureSample<'_>) {
+    if sample.heartbeat {
+      return;
+    }
+
+    for (i, value) in [sample.x, sample.y, sample.z].into_iter().enumerate() {
+      self.min[i] = self.min[i].min(value);
+      self.max[i] = self.max[i].max(value);
+      self.sum[i] += value;
+    }
+
+    self.count = self.count.saturating_add(1);
+  }
+
+  fn stats(&self) -> Option<BatchStats> {
+    if self.count == 0 {
+      return None;
+    }
+
+    let count = f64::from(self.count);
+
+    Some(BatchStats {
+      x_min: self.min[0],
+      x_max: self.max[0],
+      x_mean: self.sum[0] / count,
+      y_min: self.min[1],
+      y_max: self.max[1],
+      y_mean: self.sum[1] / count,
+      z_min: self.min[2],
+      z_max: self.max[2],
+      z_mean: self.sum[2] / count,
+    })
+  }
 }
 
 impl<'a> StreamBatcher<'a> {
-  pub fn new(batch_size: usize) -> Self {
+  pub fn new(batch_size: usize, yure_id: &'a str, user_agent: &'a str) -> Self {
+    Self {
+      strategy: BatchStrategy::Size(batch_size),
+      batch_started: None,
+      out: Vec::new(),
+      pending: 0,
+      samples_crc: !0,
+      yure_id,
+      user_agent,
+      envelope_version: 1,
+      crc: false,
+      stats: None,
+      units: None,
+      batch_seq: 0,
+    }
+  }
+
+  pub fn with_envelope_version(mut self, envelope_version: u8) -> Self {
+    self.envelope_version = envelope_version;
+    self
+  }
+
+  pub fn with_strategy(mut self, strategy: BatchStrategy) -> Self {
+    self.strategy = strategy;
+    self
+  }
+
+  pub fn with_crc(mut self, crc: bool) -> Self {
+    self.crc = crc;
+    self
+  }
+
+  pub fn with_stats(mut self, stats: bool) -> Self {
+    self.stats = stats.then(StatsAccumulator::default);
+    self
+  }
+
+  pub fn with_units(mut self, units: bool) -> Self {
+    self.units = units.then_some(SI_UNITS);
+    self
+  }
+
+  pub fn push_sample(&mut self, sample: &YureSample<'_>, now: Instant) -> Result<Option<String>> {
+    let mut out = std::mem::take(&mut self.out);
+    let result = self
+      .push_sample_into(sample, &mut out, now)
+      .and_then(|()| self.flush_into(&mut out, now));
+
+    self.finish_batch(out, result)
+  }
+
+  // When a time or hybrid batch in progress falls due without another push.
+  pub fn deadline(&self) -> Option<Instant> {
+    self.strategy.deadline(self.batch_started)
+  }
+
+  pub fn flush_due(&mut self, now: Instant) -> Result<Option<String>> {
+    let mut out = std::mem::take(&mut self.out);
+    let result = self.flush_into(&mut out, now);
+
+    self.finish_batch(out, result)
+  }
+
+  // Bumped each time a batch completes or is abandoned.
+  pub fn batch_seq(&self) -> u64 {
+    self.batch_seq
+  }
+
+  pub fn batch_size(&self) -> Option<usize> {
+    match self.strategy {
+      BatchStrategy::Size(size) | BatchStrategy::Hybrid(size, _) => Some(size),
+      BatchStrategy::Time(_) => None,
+    }
+  }
+
+  // The pending samples are kept; shrinking below them completes the batch
+  // right away instead of waiting for the next push.
+  pub fn resize(&mut self, new_size: usize, now: Instant) -> Result<Option<String>> {
+    let new_size = new_size.max(1);
+
+    self.strategy = match self.strategy {
+      BatchStrategy::Size(_) => BatchStrategy::Size(new_size),
+      BatchStrategy::Hybrid(_, interval) => BatchStrategy::Hybrid(new_size, interval),
+      BatchStrategy::Time(interval) => BatchStrategy::Time(interval),
+    };
+
+    let mut out = std::mem::take(&mut self.out);
+    let result = self.flush_into(&mut out, now);
+
+    self.finish_batch(out, result)
+  }
+
+  fn finish_batch(&mut self, mut out: Vec<u8>, result: Result<bool>) -> Result<Option<String>> {
+    match result {
+      Ok(true) => {
+        // The finished buffer leaves by value: tungstenite takes the String,
+        // copies it into its write buffer and drops it, so it never comes
+        // back for reuse. That costs one allocation per batch, presized from
+        // the last batch so serializing into it does not regrow.
+        self.out = Vec::with_capacity(out.len());
+
+        String::from_utf8(out)
+          .map(Some)
+          .map_err(|err| Error::invalid_state_with("batch json is not valid utf-8", err))
+      }
+
+      Ok(false) => {
+        self.out = out;
+
+        Ok(None)
+      }
+
+      Err(err) => {
+        out.clear();
+        self.out = out;
+        self.reset();
+
+        Err(err)
+      }
+    }
+  }
+
+  pub fn push_sample_into<W: Write>(
+    &mut self,
+    sample: &YureSample<'_>,
+    writer: &mut W,
+    now: Instant,
+  ) -> Result<()> {
+    if self.pending == 0 {
+      self.write_header(writer)?;
+      self.batch_started = Some(now);
+    }
+
+    let mut samples = CrcWriter {
+      inner: writer,
+      crc: &mut self.samples_crc,
+    };
+
+    write_bytes(&mut samples, if self.pending == 0 { b"[" } else { b"," })?;
+
+    if self.envelope_version == 0 {
+      let legacy = LegacySample {
+        yure_id: self.yure_id,
+        user_agent: self.user_agent,
+        sample,
+      };
+
+      serde_json::to_writer(&mut samples, &legacy)?;
+    } else {
+      serde_json::to_writer(&mut samples, sample)?;
+    }
+
+    if let Some(stats) = self.stats.as_mut() {
+      stats.push(sample);
+    }
+
+    self.pending += 1;
+
+    Ok(())
+  }
+
+  pub fn flush_into<W: Write>(&mut self, writer: &mut W, now: Instant) -> Result<bool> {
+    if self.pending == 0 || !self.strategy.is_due(self.pending, self.batch_started, now) {
+      return Ok(false);
+    }
+
+    let mut samples = CrcWriter {
+      inner: &mut *writer,
+      crc: &mut self.samples_crc,
+    };
+
+    write_bytes(&mut samples, b"]")?;
+
+    let crc = !self.samples_crc;
+    let legacy_bare = self.envelope_version == 0 && !self.crc;
+
+    if self.envelope_version != 0
+      && let Some(stats) = self.stats.as_ref().and_then(StatsAccumulator::stats)
+    {
+      write_bytes(writer, b",\"stats\":")?;
+      serde_json::to_writer(&mut *writer, &stats)?;
+    }
+
+    if self.crc {
+      write_bytes(writer, format!(",\"crc\":{crc}").as_bytes())?;
+    }
+
+    if !legacy_bare {
+      write_bytes(writer, b"}")?;
+    }
+
+    self.reset();
+
+    Ok(true)
+  }
+
+  fn write_header<W: Write>(&self, writer: &mut W) -> Result<()> {
+    if self.envelope_version == 0 {
+      if self.crc {
+        write_bytes(
+          writer,
+          format!("{{\"batchSeq\":{},\"samples\":", self.batch_seq).as_bytes(),
+        )?;
+      }
+
+      return Ok(());
+    }
+
+    write_bytes(
+      writer,
+      format!(
+        "{{\"v\":{},\"batchSeq\":{},\"yureId\":",
+        self.envelope_version, self.batch_seq
+      )
+      .as_bytes(),
+    )?;
+    serde_json::to_writer(&mut *writer, self.yure_id)?;
+    write_bytes(writer, b",\"ua\":")?;
+    serde_json::to_writer(&mut *writer, self.user_agent)?;
+
+    if let Some(units) = self.units.as_ref() {
+      write_bytes(writer, b",\"units\":")?;
+      serde_json::to_writer(&mut *writer, units)?;
+    }
+
+    write_bytes(writer, b",\"samples\":")
+  }
+
+  // Batches abandoned on a write error still consume a sequence number so
+  // the server sees the gap.
+  fn reset(&mut self) {
+    self.batch_seq = self.batch_seq.wrapping_add(1);
+    self.pending = 0;
+    self.batch_started = None;
+    self.samples_crc = !0;
+
+    if let Some(stats) = self.stats.as_mut() {
+      *stats = StatsAccumulator::default();
+    }
+  }
+}
+
+impl<'a, W: Write> CsvWriter<'a, W> {
+  pub fn new(writer: W, yure_id: &'a str, user_agent: &'a str) -> Self {
     Self {
-      batch_size,
-      buf: Vec::with_capacity(batch_size),
+      writer,
+      yure_id,
+      user_agent,
+      header_written: false,
     }
   }
 
-  pub fn push_sample(&mut self, sample: YureSample<'a>) -> Result<Option<String>> {
-    self.buf.push(sample);
+  pub fn write_sample(&mut self, sample: &YureSample<'_>) -> Result<()> {
+    if sample.heartbeat {
+      return Ok(());
+    }
 
-    if self.buf.len() < self.batch_size {
-      return Ok(None);
+    if !self.header_written {
+      write_csv(&mut self.writer, b"yure_id,user_agent,x,y,z,t\r\n")?;
+      self.header_written = true;
     }
 
-    let json = serde_json::to_string(&self.buf).map_err(Error::from)?;
+    let row = format!(
+      "{},{},{},{},{},{}\r\n",
+      csv_field(self.yure_id),
+      csv_field(self.user_agent),
+      format_significant(sample.x),
+      format_significant(sample.y),
+      format_significant(sample.z),
+      format_significant(sample.t),
+    );
+
+    write_csv(&mut self.writer, row.as_bytes())
+  }
+
+  pub fn flush(&mut self) -> Result<()> {
+    self
+      .writer
+      .flush()
+      .map_err(|err| Error::invalid_state_with("failed to flush csv output", err))
+  }
+}
+
+impl<W: Write> Write for CrcWriter<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+
+    *self.crc = crc32_update(*self.crc, &buf[..written]);
+
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+  writer
+    .write_all(bytes)
+    .map_err(|err| Error::invalid_state_with("failed to write batch", err))
+}
+
+fn write_csv<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+  writer
+    .write_all(bytes)
+    .map_err(|err| Error::invalid_state_with("failed to write csv output", err))
+}
+
+fn csv_field(value: &str) -> Cow<'_, str> {
+  if !value.contains([',', '"', '\r', '\n']) {
+    return Cow::Borrowed(value);
+  }
 
-    self.buf.clear();
+  Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+}
 
-    Ok(Some(json))
+// Mirrors printf's %.15g, which round-trips every value an f64 sensor reading
+// can meaningfully carry while keeping rows short.
+#[allow(clippy::cast_possible_truncation)]
+fn format_significant(value: f64) -> String {
+  const DIGITS: i32 = 15;
+
+  if value == 0.0 || !value.is_finite() {
+    return value.to_string();
   }
+
+  let exponent = value.abs().log10().floor() as i32;
+
+  if !(-5..DIGITS).contains(&exponent) {
+    return format!("{value:.14e}");
+  }
+
+  let decimals = usize::try_from(DIGITS - 1 - exponent).unwrap_or(0);
+  let fixed = format!("{value:.decimals$}");
+
+  if !fixed.contains('.') {
+    return fixed;
+  }
+
+  fixed
+    .trim_end_matches('0')
+    .trim_end_matches('.')
+    .to_string()
+}
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+  bytes.iter().fold(crc, |crc, &byte| {
+    let index = (crc ^ u32::from(byte)).to_le_bytes()[0];
+
+    CRC32_TABLE[usize::from(index)] ^ (crc >> 8)
+  })
+}
+
+const fn crc32_table() -> [u32; 256] {
+  let mut table = [0_u32; 256];
+  let mut i = 0;
+  let mut value = 0_u32;
+
+  while i < table.len() {
+    let mut crc = value;
+    let mut bit = 0;
+
+    while bit < 8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0xEDB8_8320
+      } else {
+        crc >> 1
+      };
+      bit += 1;
+    }
+
+    table[i] = crc;
+    i += 1;
+    value += 1;
+  }
+
+  table
 }
 
 pub fn generate_yure_id() -> String {
-  let mut rng = rand::rng();
+  generate_yure_id_with(&mut rand::rng())
+}
 
+pub fn generate_yure_id_seeded(seed: u64) -> String {
+  generate_yure_id_with(&mut ChaCha8Rng::seed_from_u64(seed))
+}
+
+pub fn generate_yure_id_with<R: Rng + ?Sized>(rng: &mut R) -> String {
   String::from_utf8(
     (0..YURE_ID_LEN)
-      .map(|_| *YURE_ID_CHARSET.choose(&mut rng).unwrap())
+      .map(|_| *YURE_ID_CHARSET.choose(rng).unwrap())
       .collect::<Vec<u8>>(),
   )
   .unwrap()
 }
 
-pub fn generate_user_agent(algo: Algorithm, rate: u32) -> String {
-  let app_name = env!("CARGO_PKG_NAME");
-  let app_version = env!("CARGO_PKG_VERSION");
-  let arch = std::env::consts::ARCH;
-  let name = linux_os_release()
-    .map(|r| r.name().to_string())
-    .unwrap_or(os_type().unwrap_or("Unknown".into()));
-  let release = os_release().unwrap_or("unknown".into());
+pub fn generate_user_agent(template: &str, algo: Algorithm, rate: u32) -> Result<String> {
+  let algo = algo.to_string();
+  let rate = rate.to_string();
+  let name = linux_os_release().map_or_else(
+    |_| os_type().unwrap_or_else(|_| "Unknown".into()),
+    |r| r.name().to_string(),
+  );
+  let release = os_release().unwrap_or_else(|_| "unknown".into());
+  let vars = [
+    ("app", env!("CARGO_PKG_NAME")),
+    ("version", env!("CARGO_PKG_VERSION")),
+    ("algo", algo.as_str()),
+    ("rate", rate.as_str()),
+    ("os", name.as_str()),
+    ("release", release.as_str()),
+    ("arch", std::env::consts::ARCH),
+  ];
+  let user_agent = render_user_agent(template, &vars);
+
+  if user_agent.trim().is_empty() {
+    return Err(Error::invalid_state(format!(
+      "user agent template {template:?} renders to an empty string"
+    )));
+  }
+
+  Ok(user_agent)
+}
+
+// Single pass over the template, so a substituted value that happens to
+// contain a placeholder is left as is and unknown placeholders stay verbatim.
+pub fn render_user_agent(template: &str, vars: &[(&str, &str)]) -> String {
+  let mut rendered = String::with_capacity(template.len());
+  let mut rest = template;
+
+  while let Some(start) = rest.find('{') {
+    rendered.push_str(&rest[..start]);
+    rest = &rest[start..];
+
+    let value = rest.find('}').and_then(|end| {
+      let key = &rest[1..end];
+
+      vars
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| (*value, end))
+    });
+
+    if let Some((value, end)) = value {
+      rendered.push_str(value);
+      rest = &rest[end + 1..];
+    } else {
+      rendered.push('{');
+      rest = &rest[1..];
+    }
+  }
+
+  rendered.push_str(rest);
+  rendered
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    Axes, BatchStrategy, FieldNames, StreamBatcher, YURE_ID_LEN, YureSample, csv_field,
+    format_significant, generate_yure_id_seeded, render_user_agent,
+  };
+  use std::time::{Duration, Instant};
 
-  format!("{app_name} v{app_version}-{algo}-{rate}hz on {name} {release} {arch}")
+  #[test]
+  fn csv_field_quotes_only_when_needed() {
+    assert_eq!(csv_field("yured/0.1.0"), "yured/0.1.0");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    assert_eq!(csv_field("two\r\nlines"), "\"two\r\nlines\"");
+  }
+
+  #[test]
+  fn format_significant_keeps_fifteen_digits() {
+    assert_eq!(format_significant(0.0), "0");
+    assert_eq!(format_significant(9.806_65), "9.80665");
+    assert_eq!(format_significant(-0.1 - 0.2), "-0.3");
+    assert_eq!(format_significant(1_700_000_000_123.5), "1700000000123.5");
+    assert_eq!(format_significant(100.0), "100");
+    assert_eq!(format_significant(1.5e-7), "1.50000000000000e-7");
+    assert_eq!(format_significant(f64::NAN), "NaN");
+  }
+
+  #[test]
+  fn batch_strategy_parses_each_form() {
+    assert_eq!(
+      BatchStrategy::parse("size:10").unwrap(),
+      BatchStrategy::Size(10)
+    );
+    assert_eq!(
+      BatchStrategy::parse(" time:250 ").unwrap(),
+      BatchStrategy::Time(Duration::from_millis(250))
+    );
+    assert_eq!(
+      BatchStrategy::parse("hybrid:5:100").unwrap(),
+      BatchStrategy::Hybrid(5, Duration::from_millis(100))
+    );
+
+    for spec in [
+      "size:0", "time:0", "time:-1", "hybrid:5", "size", "count:5", "",
+    ] {
+      assert!(BatchStrategy::parse(spec).is_err(), "{spec:?} parsed");
+    }
+  }
+
+  #[test]
+  fn batch_strategy_is_due_by_size_or_elapsed_time() {
+    let started = Instant::now();
+    let interval = Duration::from_millis(100);
+    let before = started + Duration::from_millis(99);
+    let after = started + interval;
+
+    assert!(!BatchStrategy::Size(3).is_due(2, Some(started), after));
+    assert!(BatchStrategy::Size(3).is_due(3, Some(started), started));
+
+    assert!(!BatchStrategy::Time(interval).is_due(1000, Some(started), before));
+    assert!(BatchStrategy::Time(interval).is_due(1, Some(started), after));
+    assert!(!BatchStrategy::Time(interval).is_due(1, None, after));
+
+    assert!(BatchStrategy::Hybrid(3, interval).is_due(3, Some(started), started));
+    assert!(BatchStrategy::Hybrid(3, interval).is_due(1, Some(started), after));
+    assert!(!BatchStrategy::Hybrid(3, interval).is_due(2, Some(started), before));
+  }
+
+  #[test]
+  fn seeded_yure_id_is_reproducible() {
+    let first = generate_yure_id_seeded(42);
+
+    assert_eq!(first.len(), YURE_ID_LEN);
+    assert_eq!(first, generate_yure_id_seeded(42));
+    assert_ne!(first, generate_yure_id_seeded(43));
+  }
+
+  #[test]
+  fn user_agent_placeholders_render_in_one_pass() {
+    let vars = [("app", "yured"), ("rate", "{app}"), ("algo", "vqf")];
+
+    assert_eq!(
+      render_user_agent("{app}/{algo} rate={rate} {missing} {", &vars),
+      "yured/vqf rate={app} {missing} {"
+    );
+  }
+
+  #[test]
+  fn shrinking_below_pending_completes_the_batch() {
+    let names = FieldNames::default();
+    let sample = YureSample {
+      names: &names,
+      sensor: None,
+      axes: Axes::default(),
+      x: 0.0,
+      y: 0.0,
+      z: 9.8,
+      m: None,
+      raw: None,
+      gyro: None,
+      gyro_axes: None,
+      tilt: None,
+      t: 0.0,
+      accel_raw: None,
+      gyro_raw: None,
+      q: None,
+      rms: None,
+      heartbeat: false,
+    };
+    let now = Instant::now();
+    let mut batch = StreamBatcher::new(10, "YUREyureYUR", "yured");
+
+    for _ in 0..3 {
+      assert!(batch.push_sample(&sample, now).unwrap().is_none());
+    }
+
+    assert!(batch.resize(20, now).unwrap().is_none());
+    assert_eq!(batch.batch_seq(), 0);
+
+    let json = batch.resize(2, now).unwrap().expect("batch completed");
+
+    assert_eq!(batch.batch_size(), Some(2));
+    assert_eq!(batch.batch_seq(), 1);
+    assert_eq!(json.matches("9.8").count(), 3);
+    assert!(batch.resize(1, now).unwrap().is_none());
+  }
+
+  #[test]
+  fn renames_may_not_take_a_fixed_field_name() {
+    assert!(FieldNames::parse("t=time,x=ax").is_ok());
+
+    for spec in ["t=q", "x=pitch", "y=sensor", "z=heartbeat", "yureId=gx"] {
+      assert!(FieldNames::parse(spec).is_err(), "{spec:?} parsed");
+    }
+
+    assert!(FieldNames::default().with_magnitude("rms").is_err());
+    assert!(FieldNames::parse("x=m").is_ok());
+    assert!(
+      FieldNames::parse("x=m")
+        .unwrap()
+        .with_magnitude("m")
+        .is_err()
+    );
+  }
 }
@@ -0,0 +1,48 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[test]
+fn synthetic_dry_run_emits_samples() {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_yured"))
+    .args([
+      "--synthetic",
+      "--dry-run",
+      "--verbose",
+      "--batch",
+      "5",
+      "--rate",
+      "50",
+    ])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+  let stdout = child.stdout.take().unwrap();
+  let started = Instant::now();
+  let samples: Vec<serde_json::Value> = BufReader::new(stdout)
+    .lines()
+    .map_while(Result::ok)
+    .filter_map(|line| serde_json::from_str(&line).ok())
+    .take(5)
+    .collect();
+
+  let _ = child.kill();
+  let _ = child.wait();
+
+  assert_eq!(samples.len(), 5);
+  assert!(started.elapsed() < Duration::from_secs(2));
+
+  for sample in &samples {
+    for axis in ["x", "y", "z"] {
+      let value = sample[axis].as_f64().unwrap();
+
+      assert!(value.is_finite(), "{axis} is not finite in {sample}");
+    }
+
+    assert!(
+      sample["t"].as_f64().unwrap() > 0.0,
+      "t is not positive in {sample}"
+    );
+  }
+}